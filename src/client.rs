@@ -0,0 +1,196 @@
+//! Builders for the program instructions, meant for off-chain callers.
+//!
+//! Each builder derives the program PDAs and lists the accounts in the order documented on the
+//! matching instruction handler.
+
+use solana_program::{instruction::{AccountMeta, Instruction}, pubkey::Pubkey, sysvar};
+use solana_sdk_ids::system_program;
+use spl_associated_token_account_client::address::get_associated_token_address_with_program_id;
+
+use crate::error::InvoiceError;
+use crate::instruction::{CreateInvoiceArgs, InstructionData};
+use crate::state::{Invoice, CONFIG_SEED, MERCHANT_SEED, TREASURY_SEED, VAULT_SEED};
+
+/// Address of the invoice `id` issued by `issuer`.
+pub fn find_invoice_address(program_id: &Pubkey, issuer: &Pubkey, id: u128) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[issuer.as_ref(), &id.to_be_bytes()], program_id)
+}
+
+/// Address of the invoice `id` of the first program version, migrated invoices keep it.
+pub fn find_legacy_invoice_address(program_id: &Pubkey, id: u128) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[&id.to_be_bytes()], program_id)
+}
+
+/// Address of the program config.
+pub fn find_config_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[CONFIG_SEED], program_id)
+}
+
+/// Address of the registry entry of `merchant`.
+pub fn find_merchant_address(program_id: &Pubkey, merchant: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[MERCHANT_SEED, merchant.as_ref()], program_id)
+}
+
+/// Address of the treasury collecting protocol fees.
+pub fn find_treasury_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[TREASURY_SEED], program_id)
+}
+
+/// Address of the vault holding the payments of an escrow invoice.
+pub fn find_vault_address(program_id: &Pubkey, invoice: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[VAULT_SEED, invoice.as_ref()], program_id)
+}
+
+/// Creates an invoice issued by `merchant`.
+///
+/// `token_program` owns the invoice mint and is only used for escrow invoices paid in tokens.
+///
+/// # Errors
+///
+/// Returns `InvalidTokenProgram` if `token_program` is `None` for an escrow invoice paid in tokens.
+pub fn create_invoice_ix(
+    program_id: &Pubkey,
+    merchant: &Pubkey,
+    token_program: Option<&Pubkey>,
+    args: CreateInvoiceArgs,
+) -> Result<Instruction, InvoiceError> {
+    let (invoice, _) = find_invoice_address(program_id, merchant, args.id);
+
+    let mut accounts = vec![
+        AccountMeta::new(*merchant, true),
+        AccountMeta::new(invoice, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(find_merchant_address(program_id, merchant).0, false),
+        AccountMeta::new_readonly(find_config_address(program_id).0, false),
+    ];
+
+    if args.escrow.is_some() {
+        accounts.push(AccountMeta::new(find_vault_address(program_id, &invoice).0, false));
+        if let Some(mint) = args.mint {
+            accounts.push(AccountMeta::new_readonly(mint, false));
+            accounts.push(AccountMeta::new_readonly(*token_program.ok_or(InvoiceError::InvalidTokenProgram)?, false));
+        }
+    }
+
+    Ok(Instruction::new_with_borsh(*program_id, &InstructionData::CreateInvoice(Box::new(args)), accounts))
+}
+
+/// Pays `invoice`, as stored on chain, from the `sender` wallet, or its associated token account
+/// for token invoices.
+///
+/// Pays the whole outstanding balance when `amount` is `None`. `token_program` owns the invoice
+/// mint and is only used for token invoices.
+///
+/// # Errors
+///
+/// Returns `InvalidTokenProgram` if `token_program` is `None` for a token invoice.
+pub fn pay_invoice_ix(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    invoice: &Invoice,
+    token_program: Option<&Pubkey>,
+    amount: Option<u64>,
+) -> Result<Instruction, InvoiceError> {
+    let (invoice_key, _) = if invoice.legacy_address {
+        find_legacy_invoice_address(program_id, invoice.id)
+    } else {
+        find_invoice_address(program_id, &invoice.issuer, invoice.id)
+    };
+    let (treasury, _) = find_treasury_address(program_id);
+    let destination = Pubkey::new_from_array(invoice.destination);
+    let escrow = invoice.escrow.is_some();
+
+    let mut accounts = vec![
+        AccountMeta::new(*sender, true),
+        AccountMeta::new(invoice_key, false),
+        AccountMeta::new(if escrow { find_vault_address(program_id, &invoice_key).0 } else { destination }, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(find_config_address(program_id).0, false),
+        AccountMeta::new(treasury, false),
+    ];
+
+    let token = match (invoice.mint, token_program) {
+        (Some(mint), Some(token_program)) => Some((mint, token_program)),
+        (Some(_), None) => return Err(InvoiceError::InvalidTokenProgram),
+        (None, _) => None,
+    };
+    let token_account = |wallet: &Pubkey| match token {
+        Some((mint, token_program)) => get_associated_token_address_with_program_id(wallet, &mint, token_program),
+        None => *wallet,
+    };
+
+    if let Some((mint, token_program)) = token {
+        accounts.push(AccountMeta::new_readonly(mint, false));
+        accounts.push(AccountMeta::new_readonly(*token_program, false));
+        accounts.push(AccountMeta::new(token_account(sender), false));
+        if !escrow {
+            accounts.push(AccountMeta::new(token_account(&destination), false));
+            accounts.push(AccountMeta::new(token_account(&treasury), false));
+        }
+    }
+
+    accounts.extend(invoice.splits.iter().map(|split| AccountMeta::new(token_account(&split.recipient), false)));
+
+    let data = match amount {
+        Some(amount) => InstructionData::PayInvoicePartial { amount },
+        None => InstructionData::PayInvoice,
+    };
+
+    Ok(Instruction::new_with_borsh(*program_id, &data, accounts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::EscrowArgs;
+
+    #[test]
+    fn create_invoice_ix_lists_vault_of_escrow_invoices() {
+        let program_id = Pubkey::new_unique();
+        let merchant = Pubkey::new_unique();
+        let args = CreateInvoiceArgs { escrow: Some(EscrowArgs { arbiter: None, timeout: 60 }), ..CreateInvoiceArgs::sample(None) };
+
+        let ix = create_invoice_ix(&program_id, &merchant, None, args.clone()).unwrap();
+
+        let (invoice, _) = find_invoice_address(&program_id, &merchant, 1);
+        assert_eq!(ix.accounts.len(), 7);
+        assert_eq!(ix.accounts[0], AccountMeta::new(merchant, true));
+        assert_eq!(ix.accounts[1], AccountMeta::new(invoice, false));
+        assert_eq!(ix.accounts[6], AccountMeta::new(find_vault_address(&program_id, &invoice).0, false));
+
+        let token_args = CreateInvoiceArgs { mint: Some(Pubkey::new_unique()), ..args };
+        assert_eq!(create_invoice_ix(&program_id, &merchant, None, token_args), Err(InvoiceError::InvalidTokenProgram));
+    }
+
+    #[test]
+    fn pay_invoice_ix_uses_associated_token_accounts() {
+        let program_id = Pubkey::new_unique();
+        let sender = Pubkey::new_unique();
+        let token_program = spl_token_2022::id();
+        let mint = Pubkey::new_unique();
+        let args = CreateInvoiceArgs::sample(Some(mint));
+        let invoice = Invoice {
+            rent_receiver: Pubkey::new_unique(),
+            mint: args.mint,
+            splits: args.splits.clone(),
+            ..Invoice::open(args.id, Pubkey::new_unique(), args.amount, args.destination, 255)
+        };
+
+        let ix = pay_invoice_ix(&program_id, &sender, &invoice, Some(&token_program), Some(400)).unwrap();
+
+        let ata = |wallet: &Pubkey| get_associated_token_address_with_program_id(wallet, &mint, &token_program);
+        let (treasury, _) = find_treasury_address(&program_id);
+        assert_eq!(ix.data, borsh::to_vec(&InstructionData::PayInvoicePartial { amount: 400 }).unwrap());
+        assert_eq!(ix.accounts.len(), 13);
+        assert_eq!(ix.accounts[0], AccountMeta::new(sender, true));
+        assert_eq!(ix.accounts[7], AccountMeta::new_readonly(mint, false));
+        assert_eq!(ix.accounts[9], AccountMeta::new(ata(&sender), false));
+        assert_eq!(ix.accounts[10], AccountMeta::new(ata(&Pubkey::new_from_array(args.destination)), false));
+        assert_eq!(ix.accounts[11], AccountMeta::new(ata(&treasury), false));
+        assert_eq!(ix.accounts[12], AccountMeta::new(ata(&args.splits[0].recipient), false));
+
+        assert_eq!(pay_invoice_ix(&program_id, &sender, &invoice, None, None), Err(InvoiceError::InvalidTokenProgram));
+    }
+}
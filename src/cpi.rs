@@ -0,0 +1,275 @@
+//! Wrappers invoking the program from other on-chain programs, enabled by the `cpi` feature.
+//!
+//! Each wrapper takes the accounts of the instruction in the order documented on the matching
+//! handler and invokes the program with them. The `_signed` variants sign for PDAs of the calling
+//! program, e.g. a merchant or a payer owned by it.
+
+use solana_program::{account_info::AccountInfo, entrypoint::ProgramResult, instruction::{AccountMeta, Instruction}, program::invoke_signed};
+
+use crate::instruction::{CreateInvoiceArgs, InstructionData};
+
+/// Accounts of the `CreateInvoice` instruction.
+pub struct CreateInvoice<'a, 'info> {
+    pub program: &'a AccountInfo<'info>,
+    pub merchant: &'a AccountInfo<'info>,
+    pub invoice: &'a AccountInfo<'info>,
+    pub system_program: &'a AccountInfo<'info>,
+    pub rent: &'a AccountInfo<'info>,
+    pub merchant_registry: &'a AccountInfo<'info>,
+    pub config: &'a AccountInfo<'info>,
+    /// Vault of escrow invoices, `None` otherwise
+    pub vault: Option<&'a AccountInfo<'info>>,
+    /// Mint and its token program, only for escrow invoices paid in tokens
+    pub mint: Option<&'a AccountInfo<'info>>,
+    pub token_program: Option<&'a AccountInfo<'info>>,
+}
+
+/// Accounts of the `PayInvoice` and `PayInvoicePartial` instructions.
+pub struct PayInvoice<'a, 'info> {
+    pub program: &'a AccountInfo<'info>,
+    pub sender: &'a AccountInfo<'info>,
+    pub invoice: &'a AccountInfo<'info>,
+    /// Destination of the invoice, or its vault for escrow invoices
+    pub destination: &'a AccountInfo<'info>,
+    pub system_program: &'a AccountInfo<'info>,
+    pub clock: &'a AccountInfo<'info>,
+    pub config: &'a AccountInfo<'info>,
+    pub treasury: &'a AccountInfo<'info>,
+    /// Mint, token program and sender token account of token invoices, `None` otherwise
+    pub mint: Option<&'a AccountInfo<'info>>,
+    pub token_program: Option<&'a AccountInfo<'info>>,
+    pub sender_token: Option<&'a AccountInfo<'info>>,
+    /// Destination and treasury associated token accounts of token invoices without escrow
+    pub destination_token: Option<&'a AccountInfo<'info>>,
+    pub treasury_token: Option<&'a AccountInfo<'info>>,
+    /// One account per split, in the order they're stored in the invoice
+    pub splits: &'a [AccountInfo<'info>],
+}
+
+/// Creates an invoice issued by `accounts.merchant`.
+pub fn create_invoice(accounts: CreateInvoice, args: CreateInvoiceArgs) -> ProgramResult {
+    create_invoice_signed(accounts, args, &[])
+}
+
+/// Creates an invoice issued by a PDA of the calling program, signed with `signers_seeds`.
+pub fn create_invoice_signed(accounts: CreateInvoice, args: CreateInvoiceArgs, signers_seeds: &[&[&[u8]]]) -> ProgramResult {
+    let metas = [
+        Some((accounts.merchant, true, true)),
+        Some((accounts.invoice, true, false)),
+        Some((accounts.system_program, false, false)),
+        Some((accounts.rent, false, false)),
+        Some((accounts.merchant_registry, false, false)),
+        Some((accounts.config, false, false)),
+        accounts.vault.map(|vault| (vault, true, false)),
+        accounts.mint.map(|mint| (mint, false, false)),
+        accounts.token_program.map(|token_program| (token_program, false, false)),
+    ];
+
+    let data = InstructionData::CreateInvoice(Box::new(args));
+    invoke(accounts.program, metas.into_iter().flatten(), &data, signers_seeds)
+}
+
+/// Pays `amount` of the invoice, or its whole outstanding balance when it's `None`.
+pub fn pay_invoice(accounts: PayInvoice, amount: Option<u64>) -> ProgramResult {
+    pay_invoice_signed(accounts, amount, &[])
+}
+
+/// Pays the invoice from a PDA of the calling program, signed with `signers_seeds`.
+pub fn pay_invoice_signed(accounts: PayInvoice, amount: Option<u64>, signers_seeds: &[&[&[u8]]]) -> ProgramResult {
+    let metas = [
+        Some((accounts.sender, true, true)),
+        Some((accounts.invoice, true, false)),
+        Some((accounts.destination, true, false)),
+        Some((accounts.system_program, false, false)),
+        Some((accounts.clock, false, false)),
+        Some((accounts.config, false, false)),
+        Some((accounts.treasury, true, false)),
+        accounts.mint.map(|mint| (mint, false, false)),
+        accounts.token_program.map(|token_program| (token_program, false, false)),
+        accounts.sender_token.map(|sender_token| (sender_token, true, false)),
+        accounts.destination_token.map(|destination_token| (destination_token, true, false)),
+        accounts.treasury_token.map(|treasury_token| (treasury_token, true, false)),
+    ];
+    let splits = accounts.splits.iter().map(|split| (split, true, false));
+
+    let data = match amount {
+        Some(amount) => InstructionData::PayInvoicePartial { amount },
+        None => InstructionData::PayInvoice,
+    };
+
+    invoke(accounts.program, metas.into_iter().flatten().chain(splits), &data, signers_seeds)
+}
+
+/// Invokes the program with `accounts`, given with whether they're writable and signers. Signers
+/// are the ones of the calling instruction or PDAs of `signers_seeds`.
+fn invoke<'a, 'info: 'a>(
+    program: &AccountInfo<'info>,
+    accounts: impl Iterator<Item = (&'a AccountInfo<'info>, bool, bool)>,
+    data: &InstructionData,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let (metas, mut infos): (Vec<_>, Vec<_>) = accounts
+        .map(|(account, writable, signer)| {
+            let meta = if writable { AccountMeta::new(*account.key, signer) } else { AccountMeta::new_readonly(*account.key, signer) };
+            (meta, account.clone())
+        })
+        .unzip();
+    infos.push(program.clone());
+
+    let instruction = Instruction::new_with_borsh(*program.key, data, metas);
+    invoke_signed(&instruction, &infos, signers_seeds)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, sync::Once};
+
+    use solana_program::{program_stubs::{set_syscall_stubs, SyscallStubs}, pubkey::Pubkey, sysvar};
+    use solana_sdk_ids::system_program;
+    use spl_associated_token_account_client::address::get_associated_token_address_with_program_id;
+
+    use super::*;
+    use crate::{
+        client::{create_invoice_ix, find_config_address, find_invoice_address, find_merchant_address, find_treasury_address, find_vault_address, pay_invoice_ix},
+        instruction::EscrowArgs,
+        state::Invoice,
+    };
+
+    thread_local! {
+        static INVOKED: RefCell<Vec<Instruction>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Records the instructions invoked by the test thread instead of running them.
+    struct RecordInvokes;
+
+    impl SyscallStubs for RecordInvokes {
+        fn sol_invoke_signed(&self, instruction: &Instruction, _account_infos: &[AccountInfo], _signers_seeds: &[&[&[u8]]]) -> ProgramResult {
+            INVOKED.with(|invoked| invoked.borrow_mut().push(instruction.clone()));
+            Ok(())
+        }
+    }
+
+    /// Runs `call` and returns the single instruction it invoked.
+    fn invoked(call: impl FnOnce() -> ProgramResult) -> Instruction {
+        static STUBS: Once = Once::new();
+        STUBS.call_once(|| {
+            set_syscall_stubs(Box::new(RecordInvokes));
+        });
+
+        INVOKED.with(|invoked| invoked.borrow_mut().clear());
+        call().unwrap();
+        let mut invoked = INVOKED.with(|invoked| invoked.take());
+        assert_eq!(invoked.len(), 1);
+        invoked.remove(0)
+    }
+
+    fn account(key: Pubkey) -> AccountInfo<'static> {
+        AccountInfo::new(Box::leak(Box::new(key)), false, false, Box::leak(Box::new(0)), &mut [], Box::leak(Box::new(Pubkey::default())), false, 0)
+    }
+
+    #[test]
+    fn create_invoice_invokes_client_instruction() {
+        let program_id = Pubkey::new_unique();
+        let merchant = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let token_program = spl_token_2022::id();
+        let args = CreateInvoiceArgs { escrow: Some(EscrowArgs { arbiter: None, timeout: 60 }), ..CreateInvoiceArgs::sample(Some(mint)) };
+        let (invoice, _) = find_invoice_address(&program_id, &merchant, args.id);
+
+        let program = account(program_id);
+        let [merchant_info, invoice, system_program, rent, merchant_registry, config, vault, mint, token_program_info] = [
+            merchant,
+            invoice,
+            system_program::id(),
+            sysvar::rent::id(),
+            find_merchant_address(&program_id, &merchant).0,
+            find_config_address(&program_id).0,
+            find_vault_address(&program_id, &invoice).0,
+            mint,
+            token_program,
+        ]
+        .map(account);
+        let accounts = CreateInvoice {
+            program: &program,
+            merchant: &merchant_info,
+            invoice: &invoice,
+            system_program: &system_program,
+            rent: &rent,
+            merchant_registry: &merchant_registry,
+            config: &config,
+            vault: Some(&vault),
+            mint: Some(&mint),
+            token_program: Some(&token_program_info),
+        };
+
+        let instruction = invoked(|| create_invoice(accounts, args.clone()));
+
+        assert_eq!(instruction, create_invoice_ix(&program_id, &merchant, Some(&token_program), args).unwrap());
+    }
+
+    #[test]
+    fn pay_invoice_invokes_client_instruction() {
+        let program_id = Pubkey::new_unique();
+        let sender = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let token_program = spl_token_2022::id();
+        let args = CreateInvoiceArgs::sample(Some(mint));
+        let issuer = Pubkey::new_unique();
+        let (invoice_key, bump) = find_invoice_address(&program_id, &issuer, args.id);
+        let invoice = Invoice { mint: args.mint, splits: args.splits.clone(), ..Invoice::open(args.id, issuer, args.amount, args.destination, bump) };
+        let ata = |wallet: &Pubkey| get_associated_token_address_with_program_id(wallet, &mint, &token_program);
+        let destination = Pubkey::new_from_array(invoice.destination);
+        let (treasury, _) = find_treasury_address(&program_id);
+
+        let program = account(program_id);
+        let [
+            sender_info,
+            invoice_info,
+            destination_info,
+            system_program,
+            clock,
+            config,
+            treasury_info,
+            mint,
+            token_program_info,
+            sender_token,
+            destination_token,
+            treasury_token,
+        ] = [
+            sender,
+            invoice_key,
+            destination,
+            system_program::id(),
+            sysvar::clock::id(),
+            find_config_address(&program_id).0,
+            treasury,
+            mint,
+            token_program,
+            ata(&sender),
+            ata(&destination),
+            ata(&treasury),
+        ]
+        .map(account);
+        let splits = [account(ata(&invoice.splits[0].recipient))];
+        let accounts = PayInvoice {
+            program: &program,
+            sender: &sender_info,
+            invoice: &invoice_info,
+            destination: &destination_info,
+            system_program: &system_program,
+            clock: &clock,
+            config: &config,
+            treasury: &treasury_info,
+            mint: Some(&mint),
+            token_program: Some(&token_program_info),
+            sender_token: Some(&sender_token),
+            destination_token: Some(&destination_token),
+            treasury_token: Some(&treasury_token),
+            splits: &splits,
+        };
+
+        let instruction = invoked(|| pay_invoice(accounts, Some(400)));
+
+        assert_eq!(instruction, pay_invoice_ix(&program_id, &sender, &invoice, Some(&token_program), Some(400)).unwrap());
+    }
+}
@@ -0,0 +1,271 @@
+use std::fmt;
+
+use solana_program::{msg, program_error::ProgramError};
+
+/// Errors returned by the invoice program.
+///
+/// Each variant is surfaced to clients as `ProgramError::Custom(code)`. Codes are part of the
+/// public interface: never reorder or reuse them, only append new variants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvoiceError {
+    /// The invoice has already been paid
+    AlreadyPaid = 0,
+    /// An invoice with this id already exists
+    AlreadyExists = 1,
+    /// The signer isn't allowed to manage invoices
+    InvalidAdmin = 2,
+    /// The destination account doesn't match the one stored in the invoice
+    DestinationMismatch = 3,
+    /// The invoice account isn't owned by this program
+    WrongOwner = 4,
+    /// The invoice account holds no data
+    InvoiceNotFound = 5,
+    /// The invoice account address isn't derived from the invoice id
+    InvalidInvoiceAddress = 6,
+    /// An unknown program was passed instead of the system program
+    InvalidSystemProgram = 7,
+    /// The config account isn't the program's initialized config PDA
+    InvalidConfig = 8,
+    /// The config account has already been initialized
+    ConfigAlreadyInitialized = 9,
+    /// There's no proposed admin to accept
+    NoPendingAdmin = 10,
+    /// The invoice issuer isn't a registered merchant
+    MerchantNotRegistered = 11,
+    /// The merchant is already registered
+    MerchantAlreadyRegistered = 12,
+    /// The merchant registry account address isn't derived from the merchant
+    InvalidMerchantAccount = 13,
+    /// The invoice has been cancelled by its issuer
+    InvoiceCancelled = 14,
+    /// The signer isn't the merchant that issued the invoice
+    InvalidIssuer = 15,
+    /// Only paid or cancelled invoices can be closed
+    InvoiceStillOpen = 16,
+    /// The account doesn't match the rent receiver recorded in the invoice
+    InvalidRentReceiver = 17,
+    /// The mint doesn't match the invoice or isn't owned by the passed token program
+    InvalidMint = 18,
+    /// The token program is neither SPL Token nor Token-2022
+    InvalidTokenProgram = 19,
+    /// The token account isn't the associated token account of the expected wallet
+    InvalidTokenAccount = 20,
+    /// The payment exceeds the outstanding balance of the invoice
+    Overpayment = 21,
+    /// The invoice has already received a partial payment
+    InvoicePartiallyPaid = 22,
+    /// The amount must be greater than zero
+    InvalidAmount = 23,
+    /// The invoice expired and can't be paid anymore
+    InvoiceExpired = 24,
+    /// The due date or expiry is invalid or moves a deadline backwards
+    InvalidDeadline = 25,
+    /// The refund exceeds what the destination received that hasn't been refunded yet
+    RefundExceedsPaid = 26,
+    /// The invoice hasn't received any payment
+    InvoiceNotPaid = 27,
+    /// The invoice has been refunded
+    InvoiceRefunded = 28,
+    /// The account doesn't match the payer recorded in the invoice
+    PayerMismatch = 29,
+    /// The invoice doesn't hold its payments in escrow
+    NotEscrowInvoice = 30,
+    /// The escrowed funds have already been released or returned
+    EscrowSettled = 31,
+    /// The invoice still holds funds in escrow
+    EscrowActive = 32,
+    /// The vault account address isn't derived from the invoice
+    InvalidVault = 33,
+    /// The escrow timeout hasn't passed yet
+    EscrowLocked = 34,
+    /// The signer isn't allowed to settle the escrow
+    InvalidEscrowAuthority = 35,
+    /// The payment splits are malformed or exceed the invoice amount net of the protocol fee
+    InvalidSplits = 36,
+    /// The account doesn't match the split recipient recorded in the invoice
+    InvalidSplitRecipient = 37,
+    /// The protocol fee exceeds 100%
+    InvalidFee = 38,
+    /// The treasury account address isn't the program's treasury PDA
+    InvalidTreasury = 39,
+    /// The subscription account isn't an initialized subscription PDA of this program
+    InvalidSubscription = 40,
+    /// The current billing period of the subscription hasn't elapsed yet
+    SubscriptionNotDue = 41,
+    /// The signer is neither the subscriber nor the merchant of the subscription
+    InvalidSubscriptionAuthority = 42,
+    /// The account data doesn't start with the discriminator of the expected account type
+    InvalidAccountType = 43,
+    /// The account layout version isn't supported by this program
+    UnsupportedAccountVersion = 44,
+    /// The account already uses the current layout
+    AccountUpToDate = 45,
+    /// The memo or line items of the invoice exceed their limits
+    InvalidMetadata = 46,
+    /// The line item totals overflow
+    LineItemsOverflow = 47,
+    /// The invoice amount doesn't equal the sum of its line items
+    AmountMismatch = 48,
+    /// The mint has a transfer fee or transfer hook, which invoice payments don't support
+    UnsupportedMint = 49,
+}
+
+impl InvoiceError {
+    /// Decodes a `ProgramError::Custom` code back into an `InvoiceError`.
+    pub fn from_code(code: u32) -> Option<Self> {
+        let error = match code {
+            0 => Self::AlreadyPaid,
+            1 => Self::AlreadyExists,
+            2 => Self::InvalidAdmin,
+            3 => Self::DestinationMismatch,
+            4 => Self::WrongOwner,
+            5 => Self::InvoiceNotFound,
+            6 => Self::InvalidInvoiceAddress,
+            7 => Self::InvalidSystemProgram,
+            8 => Self::InvalidConfig,
+            9 => Self::ConfigAlreadyInitialized,
+            10 => Self::NoPendingAdmin,
+            11 => Self::MerchantNotRegistered,
+            12 => Self::MerchantAlreadyRegistered,
+            13 => Self::InvalidMerchantAccount,
+            14 => Self::InvoiceCancelled,
+            15 => Self::InvalidIssuer,
+            16 => Self::InvoiceStillOpen,
+            17 => Self::InvalidRentReceiver,
+            18 => Self::InvalidMint,
+            19 => Self::InvalidTokenProgram,
+            20 => Self::InvalidTokenAccount,
+            21 => Self::Overpayment,
+            22 => Self::InvoicePartiallyPaid,
+            23 => Self::InvalidAmount,
+            24 => Self::InvoiceExpired,
+            25 => Self::InvalidDeadline,
+            26 => Self::RefundExceedsPaid,
+            27 => Self::InvoiceNotPaid,
+            28 => Self::InvoiceRefunded,
+            29 => Self::PayerMismatch,
+            30 => Self::NotEscrowInvoice,
+            31 => Self::EscrowSettled,
+            32 => Self::EscrowActive,
+            33 => Self::InvalidVault,
+            34 => Self::EscrowLocked,
+            35 => Self::InvalidEscrowAuthority,
+            36 => Self::InvalidSplits,
+            37 => Self::InvalidSplitRecipient,
+            38 => Self::InvalidFee,
+            39 => Self::InvalidTreasury,
+            40 => Self::InvalidSubscription,
+            41 => Self::SubscriptionNotDue,
+            42 => Self::InvalidSubscriptionAuthority,
+            43 => Self::InvalidAccountType,
+            44 => Self::UnsupportedAccountVersion,
+            45 => Self::AccountUpToDate,
+            46 => Self::InvalidMetadata,
+            47 => Self::LineItemsOverflow,
+            48 => Self::AmountMismatch,
+            49 => Self::UnsupportedMint,
+            _ => return None,
+        };
+        Some(error)
+    }
+}
+
+impl fmt::Display for InvoiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::AlreadyPaid => "invoice is already paid",
+            Self::AlreadyExists => "invoice with this id already exists",
+            Self::InvalidAdmin => "access denied. Invalid admin account",
+            Self::DestinationMismatch => "destination wallet is invalid",
+            Self::WrongOwner => "invoice account isn't owned by the program",
+            Self::InvoiceNotFound => "invoice account is empty",
+            Self::InvalidInvoiceAddress => "invoice account address doesn't match the invoice id",
+            Self::InvalidSystemProgram => "unknown program was passed instead of system program",
+            Self::InvalidConfig => "config account is invalid or not initialized",
+            Self::ConfigAlreadyInitialized => "config account is already initialized",
+            Self::NoPendingAdmin => "there's no proposed admin to accept",
+            Self::MerchantNotRegistered => "merchant isn't registered",
+            Self::MerchantAlreadyRegistered => "merchant is already registered",
+            Self::InvalidMerchantAccount => "merchant registry account address doesn't match the merchant",
+            Self::InvoiceCancelled => "invoice is cancelled",
+            Self::InvalidIssuer => "access denied. Signer isn't the invoice issuer",
+            Self::InvoiceStillOpen => "invoice must be paid or cancelled to be closed",
+            Self::InvalidRentReceiver => "rent receiver doesn't match the invoice",
+            Self::InvalidMint => "mint doesn't match the invoice",
+            Self::InvalidTokenProgram => "unknown program was passed instead of token program",
+            Self::InvalidTokenAccount => "token account isn't the expected associated token account",
+            Self::Overpayment => "payment exceeds the outstanding balance",
+            Self::InvoicePartiallyPaid => "invoice is partially paid",
+            Self::InvalidAmount => "amount must be greater than zero",
+            Self::InvoiceExpired => "invoice is expired",
+            Self::InvalidDeadline => "invoice deadline is invalid",
+            Self::RefundExceedsPaid => "refund exceeds the refundable amount",
+            Self::InvoiceNotPaid => "invoice isn't paid",
+            Self::InvoiceRefunded => "invoice is refunded",
+            Self::PayerMismatch => "payer doesn't match the invoice",
+            Self::NotEscrowInvoice => "invoice isn't an escrow invoice",
+            Self::EscrowSettled => "escrow is already settled",
+            Self::EscrowActive => "invoice funds are held in escrow",
+            Self::InvalidVault => "vault account address doesn't match the invoice",
+            Self::EscrowLocked => "escrow timeout hasn't passed yet",
+            Self::InvalidEscrowAuthority => "access denied. Signer can't settle the escrow",
+            Self::InvalidSplits => "invoice splits are invalid",
+            Self::InvalidSplitRecipient => "split recipient doesn't match the invoice",
+            Self::InvalidFee => "protocol fee is invalid",
+            Self::InvalidTreasury => "treasury account address is invalid",
+            Self::InvalidSubscription => "subscription account is invalid",
+            Self::SubscriptionNotDue => "subscription isn't due yet",
+            Self::InvalidSubscriptionAuthority => "access denied. Signer can't manage the subscription",
+            Self::InvalidAccountType => "account type doesn't match, legacy accounts must be migrated first",
+            Self::UnsupportedAccountVersion => "account layout version isn't supported",
+            Self::AccountUpToDate => "account is already up to date",
+            Self::InvalidMetadata => "invoice metadata is too large",
+            Self::LineItemsOverflow => "line item totals overflow",
+            Self::AmountMismatch => "invoice amount doesn't match its line items",
+            Self::UnsupportedMint => "mints with transfer fees or hooks aren't supported",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for InvoiceError {}
+
+impl From<InvoiceError> for ProgramError {
+    fn from(e: InvoiceError) -> Self {
+        ProgramError::Custom(e as u32)
+    }
+}
+
+impl TryFrom<&ProgramError> for InvoiceError {
+    type Error = ();
+
+    fn try_from(error: &ProgramError) -> Result<Self, Self::Error> {
+        match error {
+            ProgramError::Custom(code) => Self::from_code(*code).ok_or(()),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Logs a program error, rendering program-specific codes with their description.
+pub fn print_program_error(error: &ProgramError) {
+    match InvoiceError::try_from(error) {
+        Ok(error) => msg!("Error: {}", error),
+        Err(()) => msg!("Error: {}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn custom_codes_round_trip() {
+        for code in 0..50 {
+            let error = InvoiceError::from_code(code).unwrap();
+            assert_eq!(ProgramError::from(error), ProgramError::Custom(code));
+            assert_eq!(InvoiceError::try_from(&ProgramError::Custom(code)), Ok(error));
+        }
+        assert_eq!(InvoiceError::from_code(50), None);
+    }
+}
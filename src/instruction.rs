@@ -0,0 +1,120 @@
+//! Instruction data of the program.
+//!
+//! The accounts each instruction takes are documented on its handler, [`crate::client`] builds
+//! complete instructions for the most common ones.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
+
+use crate::state::{InvoiceMetadata, Split};
+
+/// Arguments of the `CreateInvoice` instruction.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct CreateInvoiceArgs {
+    pub id: u128,
+    pub amount: u64,
+    pub destination: [u8; 32],
+    pub rent_receiver: Option<Pubkey>,
+    pub mint: Option<Pubkey>,
+    pub due_at: Option<i64>,
+    pub expires_at: Option<i64>,
+    pub escrow: Option<EscrowArgs>,
+    pub splits: Vec<Split>,
+    pub metadata: InvoiceMetadata,
+}
+
+#[cfg(test)]
+impl CreateInvoiceArgs {
+    /// Arguments of invoice 1 for 1_000 with one basis point split, shared by the unit tests.
+    pub(crate) fn sample(mint: Option<Pubkey>) -> Self {
+        CreateInvoiceArgs {
+            id: 1,
+            amount: 1_000,
+            destination: Pubkey::new_unique().to_bytes(),
+            rent_receiver: None,
+            mint,
+            due_at: None,
+            expires_at: None,
+            escrow: None,
+            splits: vec![Split { recipient: Pubkey::new_unique(), share: crate::state::Share::Bps(100) }],
+            metadata: InvoiceMetadata::default(),
+        }
+    }
+}
+
+/// Escrow terms requested when creating an invoice.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct EscrowArgs {
+    pub arbiter: Option<Pubkey>,
+    pub timeout: i64,
+}
+
+/// Arguments of the `CreateSubscription` instruction.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct CreateSubscriptionArgs {
+    pub id: u128,
+    pub destination: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub period: i64,
+    pub first_due_at: i64,
+}
+
+/// Instructions of the program, borsh encoded with the variant index as first byte.
+///
+/// Variants are part of the public interface: never reorder them, only append new ones.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub enum InstructionData {
+    PayInvoice,
+    CreateInvoice(Box<CreateInvoiceArgs>),
+    InitializeConfig {
+        admin: Pubkey,
+    },
+    SetAdmin {
+        admin: Pubkey,
+    },
+    ProposeAdmin {
+        admin: Pubkey,
+    },
+    AcceptAdmin,
+    RegisterMerchant {
+        merchant: Pubkey,
+    },
+    CancelInvoice,
+    CloseInvoice,
+    PayInvoicePartial {
+        amount: u64,
+    },
+    ExtendInvoice {
+        due_at: Option<i64>,
+        expires_at: Option<i64>,
+    },
+    RefundInvoice {
+        amount: u64,
+    },
+    ReleaseEscrow,
+    DisputeRefund,
+    SetFee {
+        fee_bps: u16,
+        fee_min: u64,
+    },
+    WithdrawTreasury {
+        mint: Option<Pubkey>,
+        amount: u64,
+    },
+    CreateSubscription(CreateSubscriptionArgs),
+    ChargeSubscription,
+    CancelSubscription,
+    MigrateInvoice,
+    UpdateInvoiceMetadata(InvoiceMetadata),
+}
+
+impl InstructionData {
+    pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        Ok(Self::try_from_slice(input)?)
+    }
+
+    pub fn pack(&self) -> Vec<u8> {
+        borsh::to_vec(self).expect("instruction data serializes into a vec")
+    }
+}
@@ -1,44 +1,92 @@
-use std::io::Write;
-
 use borsh::{BorshDeserialize, BorshSerialize};
-use solana_program::{account_info::{next_account_info, AccountInfo}, entrypoint, entrypoint::ProgramResult, msg, program::{invoke, invoke_signed}, program_error::ProgramError, pubkey::Pubkey, rent::Rent, system_instruction, system_program, sysvar::Sysvar};
+use solana_program::{account_info::{next_account_info, AccountInfo}, entrypoint::ProgramResult, msg, program::{invoke_signed, set_return_data}, program_error::ProgramError, pubkey::Pubkey, clock::Clock, rent::Rent, sysvar::Sysvar};
+use solana_sdk_ids::{bpf_loader_upgradeable, system_program};
+use solana_system_interface::instruction as system_instruction;
+use spl_associated_token_account_client::address::get_associated_token_address_with_program_id;
+use spl_token_2022::{extension::{BaseStateWithExtensions, ExtensionType, StateWithExtensions}, state::{Account, Mint}};
 
-const ADMIN_ACCOUNT_ID: &str = "HWd8ZyEzy7exV7UGLBb6Hf1it54WNPXtK5sMivepDmP";
+use crate::error::{print_program_error, InvoiceError};
+use crate::instruction::{CreateInvoiceArgs, CreateSubscriptionArgs, EscrowArgs, InstructionData};
+use crate::state::{pro_rata, AccountState, Config, Escrow, Invoice, InvoiceMetadata, InvoiceStatus, Merchant, Share, Subscription, BPS_DENOMINATOR, CONFIG_SEED, LEGACY_ADMIN, MAX_LINE_ITEMS, MAX_MEMO_LEN, MAX_SPLITS, MERCHANT_SEED, SUBSCRIPTION_SEED, TREASURY_SEED, VAULT_SEED};
 
-#[derive(BorshSerialize, BorshDeserialize, Debug)]
-struct Invoice {
-    id: u128,
-    amount: u64,
-    paid: bool,
-    destination: [u8; 32],
-}
+pub mod client;
+#[cfg(feature = "cpi")]
+pub mod cpi;
+pub mod error;
+pub mod instruction;
+pub mod state;
 
-#[derive(BorshSerialize, BorshDeserialize, Debug)]
-enum InstructionData {
-    PayInvoice,
-    CreateInvoice(Invoice)
-}
+#[cfg(not(feature = "no-entrypoint"))]
+solana_program::entrypoint!(process_instruction);
 
-entrypoint!(process_instruction);
-fn process_instruction(
+/// Decodes the instruction data and dispatches it to its handler.
+pub fn process_instruction(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
     instruction_data: &[u8],
 ) -> ProgramResult {
-    match InstructionData::try_from_slice(instruction_data)? {
-        InstructionData::PayInvoice => pay_invoice(accounts),
-        InstructionData::CreateInvoice(invoice) => create_invoice(program_id, accounts, invoice),
+    let result = match InstructionData::unpack(instruction_data)? {
+        InstructionData::PayInvoice => pay_invoice(program_id, accounts, None),
+        InstructionData::CreateInvoice(args) => create_invoice(program_id, accounts, *args),
+        InstructionData::InitializeConfig { admin } => initialize_config(program_id, accounts, admin),
+        InstructionData::SetAdmin { admin } => set_admin(program_id, accounts, admin),
+        InstructionData::ProposeAdmin { admin } => propose_admin(program_id, accounts, admin),
+        InstructionData::AcceptAdmin => accept_admin(program_id, accounts),
+        InstructionData::RegisterMerchant { merchant } => register_merchant(program_id, accounts, merchant),
+        InstructionData::CancelInvoice => cancel_invoice(program_id, accounts),
+        InstructionData::CloseInvoice => close_invoice(program_id, accounts),
+        InstructionData::PayInvoicePartial { amount } => pay_invoice(program_id, accounts, Some(amount)),
+        InstructionData::ExtendInvoice { due_at, expires_at } => extend_invoice(program_id, accounts, due_at, expires_at),
+        InstructionData::RefundInvoice { amount } => refund_invoice(program_id, accounts, amount),
+        InstructionData::ReleaseEscrow => release_escrow(program_id, accounts),
+        InstructionData::DisputeRefund => dispute_refund(program_id, accounts),
+        InstructionData::SetFee { fee_bps, fee_min } => set_fee(program_id, accounts, fee_bps, fee_min),
+        InstructionData::WithdrawTreasury { mint, amount } => withdraw_treasury(program_id, accounts, mint, amount),
+        InstructionData::CreateSubscription(args) => create_subscription(program_id, accounts, args),
+        InstructionData::ChargeSubscription => charge_subscription(program_id, accounts),
+        InstructionData::CancelSubscription => cancel_subscription(program_id, accounts),
+        InstructionData::MigrateInvoice => migrate_invoice(program_id, accounts),
+        InstructionData::UpdateInvoiceMetadata(metadata) => update_invoice_metadata(program_id, accounts, metadata),
+    };
+
+    if let Err(error) = &result {
+        print_program_error(error);
     }
+
+    result
 }
 
 /// Accounts:
 ///
-/// 0. `[signer, writable]` Debit lamports from this account
-/// 1. `[writable]` PDA account with payment data
-/// 2. `[writable]` Destination account
+/// 0. `[signer, writable]` Debit lamports or tokens from this account
+/// 1. `[writable]` PDA account with payment data, derived from the issuer and invoice id
+/// 2. `[writable]` Destination account, or the vault of escrow invoices
 /// 3. `[]` System program
+/// 4. `[]` Sysvar clock program
+/// 5. `[]` Config account
+/// 6. `[writable]` Treasury PDA account
+///
+/// Token invoices additionally take:
+///
+/// 7. `[]` Invoice mint
+/// 8. `[]` SPL Token or Token-2022 program owning the mint
+/// 9. `[writable]` Sender token account
+/// 10. `[writable]` Destination associated token account, omitted for escrow invoices
+/// 11. `[writable]` Treasury associated token account, omitted for escrow invoices
+///
+/// Invoices with splits additionally take one account per split, in the order they're stored in
+/// the invoice: the recipient wallet, or its associated token account for token invoices.
+///
+/// Pays `amount`, or the whole outstanding balance when it's `None`, and returns the balance
+/// left after the payment as little-endian `u64` return data. The protocol fee of the invoice
+/// accrues with the paid amount and is taken first, the splits then take their share of the
+/// invoice amount net of the fee and the destination receives the rest; escrow invoices are
+/// charged when their funds are released. Once partially paid, the rest of the invoice can only be paid by the same payer,
+/// the one refunds go to.
 fn pay_invoice(
+    program_id: &Pubkey,
     accounts: &[AccountInfo],
+    amount: Option<u64>,
 ) -> ProgramResult {
     let accounts_iter = &mut accounts.iter();
 
@@ -46,94 +94,3367 @@ fn pay_invoice(
     let pda = next_account_info(accounts_iter)?;
     let destination = next_account_info(accounts_iter)?;
     let system_program = next_account_info(accounts_iter)?;
+    let sysvar_clock_program = next_account_info(accounts_iter)?;
+    let config = next_account_info(accounts_iter)?;
+    let treasury = next_account_info(accounts_iter)?;
 
     if !sender.is_signer {
         msg!("sender isn't a transaction signer");
         return Err(ProgramError::MissingRequiredSignature);
     }
 
-    if pda.data_is_empty() {
-        msg!("pda is empty");
-        return Err(ProgramError::InvalidAccountData);
+    let mut invoice = load_invoice(program_id, pda)?;
+    check_open(&invoice)?;
+
+    let clock = Clock::from_account_info(sysvar_clock_program)?;
+    let now = clock.unix_timestamp;
+    if invoice.is_expired(now) {
+        return Err(InvoiceError::InvoiceExpired.into());
     }
 
-    if !system_program::check_id(system_program.key) {
-        msg!("unknown program was passed instead of system program");
-        return Err(ProgramError::InvalidArgument);
+    match &invoice.escrow {
+        Some(escrow) => check_vault(program_id, pda.key, escrow, destination)?,
+        None if *destination.key != Pubkey::new_from_array(invoice.destination) => {
+            return Err(InvoiceError::DestinationMismatch.into());
+        }
+        None => {}
+    }
+
+    let amount = amount.unwrap_or(invoice.outstanding());
+    if amount == 0 {
+        return Err(InvoiceError::InvalidAmount.into());
     }
 
-    let mut invoice = Invoice::try_from_slice(&pda.data.borrow())?;
+    if amount > invoice.outstanding() {
+        return Err(InvoiceError::Overpayment.into());
+    }
 
-    if destination.key.to_string() != Pubkey::new_from_array(invoice.destination).to_string() {
-        msg!("destination wallet is invalid");
-        return Err(ProgramError::InvalidArgument);
+    if invoice.amount_paid > 0 && invoice.payer != *sender.key {
+        return Err(InvoiceError::PayerMismatch.into());
     }
 
-    let instruction = system_instruction::transfer(
-        sender.key, destination.key,
-        invoice.amount,
-    );
-    invoke(&instruction, &[sender.clone(), destination.clone()])?;
+    let state = load_config(program_id, config)?;
+    check_treasury(program_id, &state, treasury)?;
 
-    invoice.paid = true;
+    let currency = Currency::from_accounts(invoice.mint, system_program, accounts_iter)?;
+    let (source, target, fee_target) = match currency {
+        Currency::Native { .. } => (sender, destination, treasury),
+        Currency::Token { .. } if invoice.escrow.is_some() => (next_account_info(accounts_iter)?, destination, treasury),
+        Currency::Token { .. } => {
+            let sender_token_account = next_account_info(accounts_iter)?;
+            let destination_token_account = next_account_info(accounts_iter)?;
+            let treasury_token_account = next_account_info(accounts_iter)?;
+            currency.check_token_account(destination.key, destination_token_account)?;
+            currency.check_token_account(treasury.key, treasury_token_account)?;
+            (sender_token_account, destination_token_account, treasury_token_account)
+        }
+    };
 
-    let mut data = pda.data.borrow_mut();
-    invoice.serialize(data.as_mut().by_ref())?;
+    // The fee comes off the top so splits can't route the whole payment around the treasury
+    let fee_total = if invoice.escrow.is_none() { invoice.fee } else { 0 };
+    let net_amount = invoice.amount - invoice.fee;
 
-    Ok(())
+    let mut remainder = amount;
+    let fee = pro_rata(fee_total, invoice.amount, invoice.amount_paid, invoice.amount_paid + amount);
+    if fee > 0 {
+        currency.transfer(sender, source, fee_target, fee, &[])?;
+        remainder -= fee;
+    }
+
+    for split in &invoice.splits {
+        let recipient = next_account_info(accounts_iter)?;
+        match currency {
+            Currency::Native { .. } if *recipient.key != split.recipient => {
+                return Err(InvoiceError::InvalidSplitRecipient.into());
+            }
+            Currency::Native { .. } => {}
+            Currency::Token { .. } => currency.check_token_account(&split.recipient, recipient)?,
+        }
+
+        // The fee and the split totals fit in the invoice amount, so their portions fit in the payment
+        let portion = split.portion(invoice.amount, net_amount, invoice.amount_paid, invoice.amount_paid + amount);
+        if portion > 0 {
+            currency.transfer(sender, source, recipient, portion, &[])?;
+            remainder = remainder.checked_sub(portion).ok_or(ProgramError::ArithmeticOverflow)?;
+        }
+    }
+
+    currency.transfer(sender, source, target, remainder, &[])?;
+
+    invoice.amount_paid += amount;
+    if invoice.escrow.is_none() {
+        invoice.amount_received += remainder;
+    }
+    invoice.payer = *sender.key;
+    invoice.paid_at = now;
+    invoice.paid_slot = clock.slot;
+    invoice.late |= invoice.is_overdue(now);
+    invoice.status = if invoice.outstanding() == 0 {
+        InvoiceStatus::Paid
+    } else {
+        InvoiceStatus::PartiallyPaid
+    };
+
+    set_return_data(&invoice.outstanding().to_le_bytes());
+
+    save_invoice(pda, &invoice)
+}
+
+/// Accounts:
+///
+/// 0. `[signer]` Merchant account that issued the invoice
+/// 1. `[writable]` PDA account with payment data
+fn cancel_invoice(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let issuer = next_account_info(accounts)?;
+    let pda = next_account_info(accounts)?;
+
+    let mut invoice = load_invoice(program_id, pda)?;
+
+    if *issuer.key != invoice.issuer {
+        return Err(InvoiceError::InvalidIssuer.into());
+    }
+
+    if !issuer.is_signer {
+        msg!("access denied. Issuer isn't a transaction signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    check_open(&invoice)?;
+
+    if invoice.amount_paid > 0 {
+        return Err(InvoiceError::InvoicePartiallyPaid.into());
+    }
+
+    invoice.status = InvoiceStatus::Cancelled;
+
+    save_invoice(pda, &invoice)
 }
 
 /// Accounts:
 ///
-/// 0. `[signer, writable]` Admin account
-/// 1. `[writable]` PDA account to write invoice data
+/// 0. `[signer, writable]` Merchant account issuing the invoice
+/// 1. `[writable]` PDA account to write invoice data, derived from the merchant and invoice id
 /// 2. `[]` System program
 /// 3. `[]` Sysvar rent program
+/// 4. `[]` Merchant registry account
+/// 5. `[]` Config account
+///
+/// Escrow invoices additionally take:
+///
+/// 6. `[writable]` Vault PDA account, derived from the invoice address
+///
+/// Escrow invoices paid in tokens additionally take:
+///
+/// 7. `[]` Invoice mint
+/// 8. `[]` SPL Token or Token-2022 program owning the mint
+///
+/// The invoice keeps the protocol fee configured at its creation, later fee changes don't apply
+/// to it.
 fn create_invoice(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
-    invoice: Invoice,
+    args: CreateInvoiceArgs,
 ) -> ProgramResult {
     let accounts = &mut accounts.iter();
 
-    let admin = next_account_info(accounts)?;
+    let merchant = next_account_info(accounts)?;
     let pda = next_account_info(accounts)?;
     let system_program = next_account_info(accounts)?;
     let sysvar_rent_program = next_account_info(accounts)?;
+    let merchant_registry = next_account_info(accounts)?;
+    let config = next_account_info(accounts)?;
 
-    if admin.key.to_string() != ADMIN_ACCOUNT_ID.to_string() {
-        msg!("access denied. Invalid admin account");
-        return Err(ProgramError::InvalidArgument);
-    }
+    load_merchant(program_id, merchant_registry, merchant.key)?;
+    let fees = load_config(program_id, config)?;
 
-    if !admin.is_signer {
-        msg!("access denied. Admin isn't a transaction signer");
+    if !merchant.is_signer {
+        msg!("access denied. Merchant isn't a transaction signer");
         return Err(ProgramError::MissingRequiredSignature);
     }
 
-    let id = invoice.id.to_be_bytes();
-    let (_, seed) = Pubkey::find_program_address(&[&id], &program_id);
-    let signer_seeds: &[&[_]] = &[&id, &[seed]];
+    if !system_program::check_id(system_program.key) {
+        return Err(InvoiceError::InvalidSystemProgram.into());
+    }
 
-    let space = borsh::object_length(&invoice)?;
-    let rent = Rent::from_account_info(sysvar_rent_program)?;
-    let minimum_balance = rent.minimum_balance(space);
+    let CreateInvoiceArgs { id, amount, destination, rent_receiver, mint, due_at, expires_at, escrow, splits, metadata } = args;
 
-    invoke_signed(
-        &system_instruction::create_account(
-            admin.key,
-            pda.key,
-            minimum_balance,
-            space as u64,
+    if amount == 0 {
+        return Err(InvoiceError::InvalidAmount.into());
+    }
+
+    let seed_id = id.to_be_bytes();
+    let (pda_key, bump) = Pubkey::find_program_address(&[merchant.key.as_ref(), &seed_id], program_id);
+    if *pda.key != pda_key {
+        return Err(InvoiceError::InvalidInvoiceAddress.into());
+    }
+
+    if pda.owner == program_id || !pda.data_is_empty() {
+        return Err(InvoiceError::AlreadyExists.into());
+    }
+
+    let escrow = match escrow {
+        Some(EscrowArgs { timeout, .. }) if timeout < 0 => return Err(InvoiceError::InvalidDeadline.into()),
+        Some(EscrowArgs { arbiter, timeout }) => {
+            let (_, vault_bump) = Pubkey::find_program_address(&[VAULT_SEED, pda.key.as_ref()], program_id);
+            Some(Escrow { arbiter, timeout, settled: false, vault_bump })
+        }
+        None => None,
+    };
+
+    let invoice = Invoice {
+        id,
+        issuer: *merchant.key,
+        amount,
+        fee: fees.fee(amount),
+        amount_paid: 0,
+        amount_received: 0,
+        amount_refunded: 0,
+        status: InvoiceStatus::Open,
+        destination,
+        rent_receiver: rent_receiver.unwrap_or(*merchant.key),
+        mint,
+        due_at: due_at.unwrap_or(0),
+        expires_at: expires_at.unwrap_or(0),
+        late: false,
+        payer: Pubkey::default(),
+        paid_at: 0,
+        paid_slot: 0,
+        escrow,
+        splits,
+        legacy_address: false,
+        bump,
+    };
+
+    check_deadlines(&invoice)?;
+    check_splits(&invoice)?;
+    check_metadata(&metadata, amount)?;
+
+    create_pda_account(
+        program_id,
+        merchant,
+        pda,
+        system_program,
+        sysvar_rent_program,
+        &[merchant.key.as_ref(), &seed_id, &[bump]],
+        invoice.space()? + metadata.space()?,
+    )?;
+
+    if let Some(escrow) = &invoice.escrow {
+        let vault = next_account_info(accounts)?;
+        check_vault(program_id, pda.key, escrow, vault)?;
+
+        let currency = Currency::from_accounts(invoice.mint, system_program, accounts)?;
+        currency.create_vault(
             program_id,
-        ),
-        &[admin.clone(), pda.clone(), system_program.clone()],
-        &[&signer_seeds],
+            merchant,
+            vault,
+            system_program,
+            sysvar_rent_program,
+            &[VAULT_SEED, pda.key.as_ref(), &[escrow.vault_bump]],
+        )?;
+    }
+
+    save_invoice(pda, &invoice)?;
+    save_invoice_metadata(pda, &invoice, &metadata)
+}
+
+/// Accounts:
+///
+/// 0. `[signer, writable]` Program upgrade authority, pays for the config account
+/// 1. `[writable]` Config PDA account
+/// 2. `[]` Program data account of this program
+/// 3. `[]` System program
+/// 4. `[]` Sysvar rent program
+/// 5. `[writable]` Treasury PDA account
+fn initialize_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    admin: Pubkey,
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let authority = next_account_info(accounts)?;
+    let config = next_account_info(accounts)?;
+    let program_data = next_account_info(accounts)?;
+    let system_program = next_account_info(accounts)?;
+    let sysvar_rent_program = next_account_info(accounts)?;
+    let treasury = next_account_info(accounts)?;
+
+    if !authority.is_signer {
+        msg!("access denied. Upgrade authority isn't a transaction signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if upgrade_authority(program_id, program_data)? != Some(*authority.key) {
+        return Err(InvoiceError::InvalidAdmin.into());
+    }
+
+    let (config_key, bump) = Pubkey::find_program_address(&[CONFIG_SEED], program_id);
+    if *config.key != config_key {
+        return Err(InvoiceError::InvalidConfig.into());
+    }
+
+    if config.owner == program_id || !config.data_is_empty() {
+        return Err(InvoiceError::ConfigAlreadyInitialized.into());
+    }
+
+    let (treasury_key, treasury_bump) = Pubkey::find_program_address(&[TREASURY_SEED], program_id);
+    if *treasury.key != treasury_key {
+        return Err(InvoiceError::InvalidTreasury.into());
+    }
+
+    let state = Config {
+        admin,
+        // Reserve room for a pending admin so proposing one never needs a realloc
+        pending_admin: Some(Pubkey::default()),
+        fee_bps: 0,
+        fee_min: 0,
+        treasury_bump,
+        bump,
+    };
+
+    create_pda_account(
+        program_id,
+        authority,
+        config,
+        system_program,
+        sysvar_rent_program,
+        &[CONFIG_SEED, &[bump]],
+        state.space()?,
     )?;
 
-    let mut data = pda.data.borrow_mut();
+    create_pda_account(
+        program_id,
+        authority,
+        treasury,
+        system_program,
+        sysvar_rent_program,
+        &[TREASURY_SEED, &[treasury_bump]],
+        0,
+    )?;
 
-    invoice.serialize(data.as_mut().by_ref())?;
+    save_config(config, Config { pending_admin: None, ..state })
+}
 
-    Ok(())
-}
\ No newline at end of file
+/// Accounts:
+///
+/// 0. `[signer]` Current admin account
+/// 1. `[writable]` Config account
+fn set_admin(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    admin: Pubkey,
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let current_admin = next_account_info(accounts)?;
+    let config = next_account_info(accounts)?;
+
+    let mut state = load_config(program_id, config)?;
+    check_admin(current_admin, &state)?;
+
+    state.admin = admin;
+    state.pending_admin = None;
+
+    save_config(config, state)
+}
+
+/// Accounts:
+///
+/// 0. `[signer]` Current admin account
+/// 1. `[writable]` Config account
+fn propose_admin(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    admin: Pubkey,
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let current_admin = next_account_info(accounts)?;
+    let config = next_account_info(accounts)?;
+
+    let mut state = load_config(program_id, config)?;
+    check_admin(current_admin, &state)?;
+
+    state.pending_admin = Some(admin);
+
+    save_config(config, state)
+}
+
+/// Accounts:
+///
+/// 0. `[signer]` Proposed admin account
+/// 1. `[writable]` Config account
+fn accept_admin(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let new_admin = next_account_info(accounts)?;
+    let config = next_account_info(accounts)?;
+
+    let mut state = load_config(program_id, config)?;
+
+    let Some(pending_admin) = state.pending_admin else {
+        return Err(InvoiceError::NoPendingAdmin.into());
+    };
+
+    if *new_admin.key != pending_admin {
+        return Err(InvoiceError::InvalidAdmin.into());
+    }
+
+    if !new_admin.is_signer {
+        msg!("access denied. Proposed admin isn't a transaction signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    state.admin = pending_admin;
+    state.pending_admin = None;
+
+    save_config(config, state)
+}
+
+/// Accounts:
+///
+/// 0. `[signer, writable]` Admin account, pays for the merchant registry account
+/// 1. `[]` Config account
+/// 2. `[writable]` Merchant registry PDA account
+/// 3. `[]` System program
+/// 4. `[]` Sysvar rent program
+fn register_merchant(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    merchant: Pubkey,
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let admin = next_account_info(accounts)?;
+    let config = next_account_info(accounts)?;
+    let merchant_registry = next_account_info(accounts)?;
+    let system_program = next_account_info(accounts)?;
+    let sysvar_rent_program = next_account_info(accounts)?;
+
+    check_admin(admin, &load_config(program_id, config)?)?;
+
+    let (merchant_registry_key, bump) = Pubkey::find_program_address(&[MERCHANT_SEED, merchant.as_ref()], program_id);
+    if *merchant_registry.key != merchant_registry_key {
+        return Err(InvoiceError::InvalidMerchantAccount.into());
+    }
+
+    if merchant_registry.owner == program_id || !merchant_registry.data_is_empty() {
+        return Err(InvoiceError::MerchantAlreadyRegistered.into());
+    }
+
+    let state = Merchant { merchant, bump };
+
+    create_pda_account(
+        program_id,
+        admin,
+        merchant_registry,
+        system_program,
+        sysvar_rent_program,
+        &[MERCHANT_SEED, merchant.as_ref(), &[bump]],
+        state.space()?,
+    )?;
+
+    state.pack(&mut merchant_registry.data.borrow_mut())
+}
+
+/// Accounts:
+///
+/// 0. `[signer]` Merchant account that issued the invoice
+/// 1. `[writable]` PDA account with payment data
+/// 2. `[writable]` Rent receiver recorded in the invoice
+///
+/// Escrow invoices additionally take:
+///
+/// 3. `[writable]` Vault PDA account
+/// 4. `[]` System program
+///
+/// Escrow invoices paid in tokens additionally take:
+///
+/// 5. `[]` Invoice mint
+/// 6. `[]` SPL Token or Token-2022 program owning the mint
+fn close_invoice(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let issuer = next_account_info(accounts)?;
+    let pda = next_account_info(accounts)?;
+    let rent_receiver = next_account_info(accounts)?;
+
+    let invoice = load_invoice(program_id, pda)?;
+
+    if *issuer.key != invoice.issuer {
+        return Err(InvoiceError::InvalidIssuer.into());
+    }
+
+    if !issuer.is_signer {
+        msg!("access denied. Issuer isn't a transaction signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if matches!(invoice.status, InvoiceStatus::Open | InvoiceStatus::PartiallyPaid) {
+        return Err(InvoiceError::InvoiceStillOpen.into());
+    }
+
+    if *rent_receiver.key != invoice.rent_receiver {
+        return Err(InvoiceError::InvalidRentReceiver.into());
+    }
+
+    if let Some(escrow) = &invoice.escrow {
+        if !escrow.settled && invoice.refundable() > 0 {
+            return Err(InvoiceError::EscrowActive.into());
+        }
+
+        let vault = next_account_info(accounts)?;
+        let system_program = next_account_info(accounts)?;
+        check_vault(program_id, pda.key, escrow, vault)?;
+
+        let currency = Currency::from_accounts(invoice.mint, system_program, accounts)?;
+        currency.close_vault(vault, rent_receiver, &[VAULT_SEED, pda.key.as_ref(), &[escrow.vault_bump]])?;
+    }
+
+    close_account(pda, rent_receiver)
+}
+
+/// Accounts:
+///
+/// 0. `[signer]` Merchant account that issued the invoice
+/// 1. `[writable]` PDA account with payment data
+///
+/// Moves the due date and/or expiry of an unpaid invoice further into the future.
+fn extend_invoice(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    due_at: Option<i64>,
+    expires_at: Option<i64>,
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let issuer = next_account_info(accounts)?;
+    let pda = next_account_info(accounts)?;
+
+    let mut invoice = load_invoice(program_id, pda)?;
+
+    if *issuer.key != invoice.issuer {
+        return Err(InvoiceError::InvalidIssuer.into());
+    }
+
+    if !issuer.is_signer {
+        msg!("access denied. Issuer isn't a transaction signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    check_open(&invoice)?;
+
+    if let Some(due_at) = due_at {
+        if invoice.due_at == 0 || due_at < invoice.due_at {
+            return Err(InvoiceError::InvalidDeadline.into());
+        }
+        invoice.due_at = due_at;
+    }
+
+    if let Some(expires_at) = expires_at {
+        if invoice.expires_at == 0 || expires_at < invoice.expires_at {
+            return Err(InvoiceError::InvalidDeadline.into());
+        }
+        invoice.expires_at = expires_at;
+    }
+
+    check_deadlines(&invoice)?;
+
+    save_invoice(pda, &invoice)
+}
+
+/// Accounts:
+///
+/// 0. `[signer, writable]` Destination account of the invoice, the refund is debited from it
+/// 1. `[writable]` PDA account with payment data
+/// 2. `[writable]` Payer recorded in the invoice
+/// 3. `[]` System program
+///
+/// Token invoices additionally take:
+///
+/// 4. `[]` Invoice mint
+/// 5. `[]` SPL Token or Token-2022 program owning the mint
+/// 6. `[writable]` Destination token account
+/// 7. `[writable]` Payer associated token account
+///
+/// The destination refunds at most what it received of the payments: the protocol fee and the
+/// split shares stay with the treasury and the split recipients.
+fn refund_invoice(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let destination = next_account_info(accounts)?;
+    let pda = next_account_info(accounts)?;
+    let payer = next_account_info(accounts)?;
+    let system_program = next_account_info(accounts)?;
+
+    let mut invoice = load_invoice(program_id, pda)?;
+
+    if *destination.key != Pubkey::new_from_array(invoice.destination) {
+        return Err(InvoiceError::DestinationMismatch.into());
+    }
+
+    if !destination.is_signer {
+        msg!("access denied. Destination isn't a transaction signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    match invoice.status {
+        InvoiceStatus::Paid | InvoiceStatus::PartiallyPaid | InvoiceStatus::PartiallyRefunded => {}
+        InvoiceStatus::Refunded => return Err(InvoiceError::InvoiceRefunded.into()),
+        InvoiceStatus::Open | InvoiceStatus::Cancelled => return Err(InvoiceError::InvoiceNotPaid.into()),
+    }
+
+    if *payer.key != invoice.payer {
+        return Err(InvoiceError::PayerMismatch.into());
+    }
+
+    if amount == 0 {
+        return Err(InvoiceError::InvalidAmount.into());
+    }
+
+    if amount > invoice.destination_refundable() {
+        return Err(InvoiceError::RefundExceedsPaid.into());
+    }
+
+    if invoice.escrow.is_some_and(|escrow| !escrow.settled) {
+        return Err(InvoiceError::EscrowActive.into());
+    }
+
+    let currency = Currency::from_accounts(invoice.mint, system_program, accounts)?;
+    let (source, target) = match currency {
+        Currency::Native { .. } => (destination, payer),
+        Currency::Token { .. } => {
+            let destination_token_account = next_account_info(accounts)?;
+            let payer_token_account = next_account_info(accounts)?;
+            currency.check_token_account(payer.key, payer_token_account)?;
+            (destination_token_account, payer_token_account)
+        }
+    };
+
+    currency.transfer(destination, source, target, amount, &[])?;
+
+    invoice.amount_refunded += amount;
+    invoice.status = if invoice.destination_refundable() == 0 {
+        InvoiceStatus::Refunded
+    } else {
+        InvoiceStatus::PartiallyRefunded
+    };
+
+    save_invoice(pda, &invoice)
+}
+
+/// Accounts:
+///
+/// 0. `[signer]` Payer or arbiter of the invoice, or its issuer once the escrow timeout passed
+/// 1. `[writable]` PDA account with payment data
+/// 2. `[writable]` Vault PDA account
+/// 3. `[writable]` Destination account
+/// 4. `[]` System program
+/// 5. `[]` Sysvar clock program
+/// 6. `[]` Config account
+/// 7. `[writable]` Treasury PDA account
+///
+/// Token invoices additionally take:
+///
+/// 8. `[]` Invoice mint
+/// 9. `[]` SPL Token or Token-2022 program owning the mint
+/// 10. `[writable]` Destination associated token account
+/// 11. `[writable]` Treasury associated token account
+fn release_escrow(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let authority = next_account_info(accounts)?;
+    let pda = next_account_info(accounts)?;
+    let vault = next_account_info(accounts)?;
+    let destination = next_account_info(accounts)?;
+    let system_program = next_account_info(accounts)?;
+    let sysvar_clock_program = next_account_info(accounts)?;
+    let config = next_account_info(accounts)?;
+    let treasury = next_account_info(accounts)?;
+
+    let mut invoice = load_invoice(program_id, pda)?;
+    let mut escrow = load_escrow(&invoice)?;
+    check_vault(program_id, pda.key, &escrow, vault)?;
+
+    if !authority.is_signer {
+        msg!("access denied. Escrow authority isn't a transaction signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if *authority.key == invoice.issuer && *authority.key != invoice.payer && Some(*authority.key) != escrow.arbiter {
+        let now = Clock::from_account_info(sysvar_clock_program)?.unix_timestamp;
+        if now <= invoice.paid_at.saturating_add(escrow.timeout) {
+            return Err(InvoiceError::EscrowLocked.into());
+        }
+    } else if *authority.key != invoice.payer && Some(*authority.key) != escrow.arbiter {
+        return Err(InvoiceError::InvalidEscrowAuthority.into());
+    }
+
+    if invoice.status != InvoiceStatus::Paid {
+        return Err(InvoiceError::InvoiceNotPaid.into());
+    }
+
+    if *destination.key != Pubkey::new_from_array(invoice.destination) {
+        return Err(InvoiceError::DestinationMismatch.into());
+    }
+
+    let state = load_config(program_id, config)?;
+    check_treasury(program_id, &state, treasury)?;
+
+    let currency = Currency::from_accounts(invoice.mint, system_program, accounts)?;
+    let (target, fee_target) = match currency {
+        Currency::Native { .. } => (destination, treasury),
+        Currency::Token { .. } => {
+            let destination_token_account = next_account_info(accounts)?;
+            let treasury_token_account = next_account_info(accounts)?;
+            currency.check_token_account(destination.key, destination_token_account)?;
+            currency.check_token_account(treasury.key, treasury_token_account)?;
+            (destination_token_account, treasury_token_account)
+        }
+    };
+
+    let vault_seeds: &[&[u8]] = &[VAULT_SEED, pda.key.as_ref(), &[escrow.vault_bump]];
+    let held = invoice.refundable();
+    let fee = invoice.fee.min(held);
+    if fee > 0 {
+        currency.transfer_from_vault(vault, fee_target, fee, vault_seeds)?;
+    }
+    currency.transfer_from_vault(vault, target, held - fee, vault_seeds)?;
+
+    escrow.settled = true;
+    invoice.escrow = Some(escrow);
+    invoice.amount_received = held - fee;
+
+    save_invoice(pda, &invoice)
+}
+
+/// Accounts:
+///
+/// 0. `[signer]` Arbiter or issuer of the invoice
+/// 1. `[writable]` PDA account with payment data
+/// 2. `[writable]` Vault PDA account
+/// 3. `[writable]` Payer recorded in the invoice
+/// 4. `[]` System program
+///
+/// Token invoices additionally take:
+///
+/// 5. `[]` Invoice mint
+/// 6. `[]` SPL Token or Token-2022 program owning the mint
+/// 7. `[writable]` Payer associated token account
+fn dispute_refund(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let authority = next_account_info(accounts)?;
+    let pda = next_account_info(accounts)?;
+    let vault = next_account_info(accounts)?;
+    let payer = next_account_info(accounts)?;
+    let system_program = next_account_info(accounts)?;
+
+    let mut invoice = load_invoice(program_id, pda)?;
+    let mut escrow = load_escrow(&invoice)?;
+    check_vault(program_id, pda.key, &escrow, vault)?;
+
+    if *authority.key != invoice.issuer && Some(*authority.key) != escrow.arbiter {
+        return Err(InvoiceError::InvalidEscrowAuthority.into());
+    }
+
+    if !authority.is_signer {
+        msg!("access denied. Escrow authority isn't a transaction signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if !matches!(invoice.status, InvoiceStatus::Paid | InvoiceStatus::PartiallyPaid) {
+        return Err(InvoiceError::InvoiceNotPaid.into());
+    }
+
+    if *payer.key != invoice.payer {
+        return Err(InvoiceError::PayerMismatch.into());
+    }
+
+    let currency = Currency::from_accounts(invoice.mint, system_program, accounts)?;
+    let target = match currency {
+        Currency::Native { .. } => payer,
+        Currency::Token { .. } => {
+            let payer_token_account = next_account_info(accounts)?;
+            currency.check_token_account(payer.key, payer_token_account)?;
+            payer_token_account
+        }
+    };
+
+    currency.transfer_from_vault(vault, target, invoice.refundable(), &[VAULT_SEED, pda.key.as_ref(), &[escrow.vault_bump]])?;
+
+    escrow.settled = true;
+    invoice.escrow = Some(escrow);
+    invoice.amount_refunded = invoice.amount_paid;
+    invoice.status = InvoiceStatus::Refunded;
+
+    save_invoice(pda, &invoice)
+}
+
+/// Accounts:
+///
+/// 0. `[signer]` Admin account
+/// 1. `[writable]` Config account
+///
+/// The new fee applies to invoices created afterwards, existing invoices keep theirs.
+fn set_fee(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    fee_bps: u16,
+    fee_min: u64,
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let admin = next_account_info(accounts)?;
+    let config = next_account_info(accounts)?;
+
+    let mut state = load_config(program_id, config)?;
+    check_admin(admin, &state)?;
+
+    if u64::from(fee_bps) > BPS_DENOMINATOR {
+        return Err(InvoiceError::InvalidFee.into());
+    }
+
+    state.fee_bps = fee_bps;
+    state.fee_min = fee_min;
+
+    save_config(config, state)
+}
+
+/// Accounts:
+///
+/// 0. `[signer]` Admin account
+/// 1. `[]` Config account
+/// 2. `[writable]` Treasury PDA account
+/// 3. `[writable]` Account receiving the withdrawn lamports
+/// 4. `[]` System program
+/// 5. `[]` Sysvar rent program
+///
+/// Token withdrawals additionally take:
+///
+/// 6. `[]` Mint of the withdrawn tokens
+/// 7. `[]` SPL Token or Token-2022 program owning the mint
+/// 8. `[writable]` Treasury associated token account
+/// 9. `[writable]` Token account receiving the withdrawn tokens
+fn withdraw_treasury(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    mint: Option<Pubkey>,
+    amount: u64,
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let admin = next_account_info(accounts)?;
+    let config = next_account_info(accounts)?;
+    let treasury = next_account_info(accounts)?;
+    let recipient = next_account_info(accounts)?;
+    let system_program = next_account_info(accounts)?;
+    let sysvar_rent_program = next_account_info(accounts)?;
+
+    let state = load_config(program_id, config)?;
+    check_admin(admin, &state)?;
+    check_treasury(program_id, &state, treasury)?;
+
+    if amount == 0 {
+        return Err(InvoiceError::InvalidAmount.into());
+    }
+
+    let currency = Currency::from_accounts(mint, system_program, accounts)?;
+    match currency {
+        Currency::Native { .. } => {
+            // The treasury has to stay rent exempt to keep collecting fees
+            let rent = Rent::from_account_info(sysvar_rent_program)?;
+            if treasury.lamports().saturating_sub(amount) < rent.minimum_balance(0) {
+                return Err(ProgramError::InsufficientFunds);
+            }
+
+            currency.transfer_from_vault(treasury, recipient, amount, &[])
+        }
+        Currency::Token { .. } => {
+            let treasury_token_account = next_account_info(accounts)?;
+            let recipient_token_account = next_account_info(accounts)?;
+            currency.check_token_account(treasury.key, treasury_token_account)?;
+
+            currency.transfer(
+                treasury,
+                treasury_token_account,
+                recipient_token_account,
+                amount,
+                &[&[TREASURY_SEED, &[state.treasury_bump]]],
+            )
+        }
+    }
+}
+
+/// Accounts:
+///
+/// 0. `[signer, writable]` Subscriber account, pays for the subscription account
+/// 1. `[signer]` Merchant account issuing the cycle invoices
+/// 2. `[writable]` Subscription PDA account, derived from the merchant and subscription id
+/// 3. `[]` Merchant registry account
+/// 4. `[]` System program
+/// 5. `[]` Sysvar rent program
+///
+/// The subscriber approves the subscription PDA as delegate of its associated token account
+/// separately, for as many cycles as it wants to prepay.
+fn create_subscription(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: CreateSubscriptionArgs,
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let subscriber = next_account_info(accounts)?;
+    let merchant = next_account_info(accounts)?;
+    let subscription = next_account_info(accounts)?;
+    let merchant_registry = next_account_info(accounts)?;
+    let system_program = next_account_info(accounts)?;
+    let sysvar_rent_program = next_account_info(accounts)?;
+
+    if !subscriber.is_signer {
+        msg!("access denied. Subscriber isn't a transaction signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if !merchant.is_signer {
+        msg!("access denied. Merchant isn't a transaction signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    load_merchant(program_id, merchant_registry, merchant.key)?;
+
+    let CreateSubscriptionArgs { id, destination, mint, amount, period, first_due_at } = args;
+
+    if amount == 0 {
+        return Err(InvoiceError::InvalidAmount.into());
+    }
+
+    if period <= 0 || first_due_at <= 0 {
+        return Err(InvoiceError::InvalidDeadline.into());
+    }
+
+    let seed_id = id.to_be_bytes();
+    let (subscription_key, bump) = Pubkey::find_program_address(&[SUBSCRIPTION_SEED, merchant.key.as_ref(), &seed_id], program_id);
+    if *subscription.key != subscription_key {
+        return Err(InvoiceError::InvalidSubscription.into());
+    }
+
+    if subscription.owner == program_id || !subscription.data_is_empty() {
+        return Err(InvoiceError::AlreadyExists.into());
+    }
+
+    let state = Subscription {
+        id,
+        merchant: *merchant.key,
+        subscriber: *subscriber.key,
+        destination,
+        mint,
+        amount,
+        period,
+        next_due_at: first_due_at,
+        cycle: 0,
+        bump,
+    };
+
+    create_pda_account(
+        program_id,
+        subscriber,
+        subscription,
+        system_program,
+        sysvar_rent_program,
+        &[SUBSCRIPTION_SEED, merchant.key.as_ref(), &seed_id, &[bump]],
+        state.space()?,
+    )?;
+
+    save_subscription(subscription, &state)
+}
+
+/// Accounts:
+///
+/// 0. `[signer, writable]` Keeper account, pays for the cycle invoice and receives its rent back
+/// 1. `[writable]` Subscription PDA account
+/// 2. `[writable]` Invoice PDA account of the current cycle
+/// 3. `[]` System program
+/// 4. `[]` Sysvar rent program
+/// 5. `[]` Sysvar clock program
+/// 6. `[]` Config account
+/// 7. `[writable]` Treasury PDA account
+/// 8. `[]` Subscription mint
+/// 9. `[]` SPL Token or Token-2022 program owning the mint
+/// 10. `[writable]` Subscriber associated token account, delegated to the subscription
+/// 11. `[writable]` Destination associated token account
+/// 12. `[writable]` Treasury associated token account
+///
+/// Charges a single cycle once it's due and records it as a paid invoice issued by the
+/// merchant. Cycles missed while the subscription couldn't be charged are skipped, not billed.
+fn charge_subscription(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let keeper = next_account_info(accounts)?;
+    let subscription = next_account_info(accounts)?;
+    let pda = next_account_info(accounts)?;
+    let system_program = next_account_info(accounts)?;
+    let sysvar_rent_program = next_account_info(accounts)?;
+    let sysvar_clock_program = next_account_info(accounts)?;
+    let config = next_account_info(accounts)?;
+    let treasury = next_account_info(accounts)?;
+
+    if !keeper.is_signer {
+        msg!("access denied. Keeper isn't a transaction signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let mut state = load_subscription(program_id, subscription)?;
+
+    let clock = Clock::from_account_info(sysvar_clock_program)?;
+    let now = clock.unix_timestamp;
+    if now < state.next_due_at {
+        return Err(InvoiceError::SubscriptionNotDue.into());
+    }
+
+    let missed = (now - state.next_due_at) / state.period;
+    let next_due_at = (missed + 1)
+        .checked_mul(state.period)
+        .and_then(|skipped| state.next_due_at.checked_add(skipped))
+        .ok_or(InvoiceError::InvalidDeadline)?;
+
+    let id = state.cycle_invoice_id(subscription.key);
+    let seed_id = id.to_be_bytes();
+    let (pda_key, bump) = Pubkey::find_program_address(&[state.merchant.as_ref(), &seed_id], program_id);
+    if *pda.key != pda_key {
+        return Err(InvoiceError::InvalidInvoiceAddress.into());
+    }
+
+    if pda.owner == program_id || !pda.data_is_empty() {
+        return Err(InvoiceError::AlreadyExists.into());
+    }
+
+    let fees = load_config(program_id, config)?;
+    check_treasury(program_id, &fees, treasury)?;
+
+    let currency = Currency::from_accounts(Some(state.mint), system_program, accounts)?;
+    let subscriber_token_account = next_account_info(accounts)?;
+    let destination_token_account = next_account_info(accounts)?;
+    let treasury_token_account = next_account_info(accounts)?;
+    currency.check_token_account(&state.subscriber, subscriber_token_account)?;
+    currency.check_token_account(&state.destination, destination_token_account)?;
+    currency.check_token_account(treasury.key, treasury_token_account)?;
+
+    let subscription_seeds: &[&[u8]] = &[SUBSCRIPTION_SEED, state.merchant.as_ref(), &state.id.to_be_bytes(), &[state.bump]];
+    let fee = fees.fee(state.amount);
+    if fee > 0 {
+        currency.transfer(subscription, subscriber_token_account, treasury_token_account, fee, &[subscription_seeds])?;
+    }
+    currency.transfer(subscription, subscriber_token_account, destination_token_account, state.amount - fee, &[subscription_seeds])?;
+
+    let invoice = Invoice {
+        id,
+        issuer: state.merchant,
+        amount: state.amount,
+        fee,
+        amount_paid: state.amount,
+        amount_received: state.amount - fee,
+        amount_refunded: 0,
+        status: InvoiceStatus::Paid,
+        destination: state.destination.to_bytes(),
+        rent_receiver: *keeper.key,
+        mint: Some(state.mint),
+        due_at: state.next_due_at,
+        expires_at: 0,
+        late: false,
+        payer: state.subscriber,
+        paid_at: now,
+        paid_slot: clock.slot,
+        escrow: None,
+        splits: vec![],
+        legacy_address: false,
+        bump,
+    };
+
+    create_pda_account(
+        program_id,
+        keeper,
+        pda,
+        system_program,
+        sysvar_rent_program,
+        &[state.merchant.as_ref(), &seed_id, &[bump]],
+        invoice.space()?,
+    )?;
+
+    save_invoice(pda, &invoice)?;
+
+    state.next_due_at = next_due_at;
+    state.cycle += 1;
+
+    save_subscription(subscription, &state)
+}
+
+/// Accounts:
+///
+/// 0. `[signer]` Subscriber or merchant of the subscription
+/// 1. `[writable]` Subscription PDA account
+/// 2. `[writable]` Subscriber account, receives the subscription rent
+fn cancel_subscription(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let authority = next_account_info(accounts)?;
+    let subscription = next_account_info(accounts)?;
+    let subscriber = next_account_info(accounts)?;
+
+    let state = load_subscription(program_id, subscription)?;
+
+    if *authority.key != state.subscriber && *authority.key != state.merchant {
+        return Err(InvoiceError::InvalidSubscriptionAuthority.into());
+    }
+
+    if !authority.is_signer {
+        msg!("access denied. Subscription authority isn't a transaction signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if *subscriber.key != state.subscriber {
+        return Err(InvoiceError::InvalidRentReceiver.into());
+    }
+
+    close_account(subscription, subscriber)
+}
+
+/// Accounts:
+///
+/// 0. `[signer, writable]` Pays for the rent of the grown invoice account
+/// 1. `[writable]` PDA account with payment data stored without account header
+/// 2. `[]` System program
+/// 3. `[]` Sysvar rent program
+///
+/// Upgrades an invoice of the first program version (layout version 1) to the current layout,
+/// growing the account to fit it. The invoice keeps its address, derived from its id alone, and
+/// is recorded as issued by the admin of that version.
+fn migrate_invoice(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let payer = next_account_info(accounts)?;
+    let pda = next_account_info(accounts)?;
+    let system_program = next_account_info(accounts)?;
+    let sysvar_rent_program = next_account_info(accounts)?;
+
+    if pda.owner != program_id {
+        return Err(InvoiceError::WrongOwner.into());
+    }
+
+    if pda.data_is_empty() {
+        return Err(InvoiceError::InvoiceNotFound.into());
+    }
+
+    if pda.data.borrow().starts_with(&Invoice::DISCRIMINATOR) {
+        return Err(InvoiceError::AccountUpToDate.into());
+    }
+
+    // Version 1 invoices hold the bare borsh layout, so the whole account has to parse
+    let legacy = LegacyInvoiceV1::try_from_slice(&pda.data.borrow())?;
+    let (pda_key, bump) = Pubkey::find_program_address(&[&legacy.id.to_be_bytes()], program_id);
+    if *pda.key != pda_key {
+        return Err(InvoiceError::InvalidInvoiceAddress.into());
+    }
+
+    if !system_program::check_id(system_program.key) {
+        return Err(InvoiceError::InvalidSystemProgram.into());
+    }
+
+    let invoice = legacy.upgrade(bump);
+    resize_legacy_account(payer, pda, system_program, sysvar_rent_program, invoice.space()?)?;
+
+    save_invoice(pda, &invoice)
+}
+
+/// Invoice as stored by the first program version, without header, at the PDA derived from its id.
+#[derive(BorshDeserialize)]
+struct LegacyInvoiceV1 {
+    id: u128,
+    amount: u64,
+    paid: bool,
+    destination: [u8; 32],
+}
+
+impl LegacyInvoiceV1 {
+    /// Maps the invoice to the current layout. Payments of that version went to the destination
+    /// in full without protocol fee, and their payer and time weren't recorded.
+    fn upgrade(self, bump: u8) -> Invoice {
+        let amount_paid = if self.paid { self.amount } else { 0 };
+        Invoice {
+            id: self.id,
+            issuer: LEGACY_ADMIN,
+            amount: self.amount,
+            fee: 0,
+            amount_paid,
+            amount_received: amount_paid,
+            amount_refunded: 0,
+            status: if self.paid { InvoiceStatus::Paid } else { InvoiceStatus::Open },
+            destination: self.destination,
+            rent_receiver: LEGACY_ADMIN,
+            mint: None,
+            due_at: 0,
+            expires_at: 0,
+            late: false,
+            payer: Pubkey::default(),
+            paid_at: 0,
+            paid_slot: 0,
+            escrow: None,
+            splits: vec![],
+            legacy_address: true,
+            bump,
+        }
+    }
+}
+
+/// Grows a legacy account to `space`, topping up its rent from `payer`.
+fn resize_legacy_account<'a>(
+    payer: &AccountInfo<'a>,
+    account: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    sysvar_rent_program: &AccountInfo<'a>,
+    space: usize,
+) -> ProgramResult {
+    let rent = Rent::from_account_info(sysvar_rent_program)?;
+    let top_up = rent.minimum_balance(space).saturating_sub(account.lamports());
+    if top_up > 0 {
+        invoke_signed(
+            &system_instruction::transfer(payer.key, account.key, top_up),
+            &[payer.clone(), account.clone(), system_program.clone()],
+            &[],
+        )?;
+    }
+
+    account.resize(space)
+}
+
+/// Accounts:
+///
+/// 0. `[signer, writable]` Merchant account that issued the invoice, pays the rent for a grown account
+/// 1. `[writable]` PDA account with payment data
+/// 2. `[writable]` Account that paid the invoice rent, receives the rent freed by a shrunk account
+/// 3. `[]` System program
+/// 4. `[]` Sysvar rent program
+///
+/// Replaces the invoice metadata, growing or shrinking the account to fit it.
+fn update_invoice_metadata(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    metadata: InvoiceMetadata,
+) -> ProgramResult {
+    let accounts = &mut accounts.iter();
+
+    let issuer = next_account_info(accounts)?;
+    let pda = next_account_info(accounts)?;
+    let rent_receiver = next_account_info(accounts)?;
+    let system_program = next_account_info(accounts)?;
+    let sysvar_rent_program = next_account_info(accounts)?;
+
+    let invoice = load_invoice(program_id, pda)?;
+
+    if *issuer.key != invoice.issuer {
+        return Err(InvoiceError::InvalidIssuer.into());
+    }
+
+    if !issuer.is_signer {
+        msg!("access denied. Issuer isn't a transaction signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if *rent_receiver.key != invoice.rent_receiver {
+        return Err(InvoiceError::InvalidRentReceiver.into());
+    }
+
+    if !system_program::check_id(system_program.key) {
+        return Err(InvoiceError::InvalidSystemProgram.into());
+    }
+
+    check_metadata(&metadata, invoice.amount)?;
+
+    let space = invoice.space()? + metadata.space()?;
+    let minimum_balance = Rent::from_account_info(sysvar_rent_program)?.minimum_balance(space);
+
+    let top_up = minimum_balance.saturating_sub(pda.lamports());
+    if top_up > 0 {
+        invoke_signed(
+            &system_instruction::transfer(issuer.key, pda.key, top_up),
+            &[issuer.clone(), pda.clone(), system_program.clone()],
+            &[],
+        )?;
+    }
+
+    pda.resize(space)?;
+    save_invoice_metadata(pda, &invoice, &metadata)?;
+
+    let refund = pda.lamports().saturating_sub(minimum_balance);
+    if refund > 0 {
+        let receiver_lamports = rent_receiver.lamports().checked_add(refund).ok_or(ProgramError::ArithmeticOverflow)?;
+        **pda.try_borrow_mut_lamports()? = minimum_balance;
+        **rent_receiver.try_borrow_mut_lamports()? = receiver_lamports;
+    }
+
+    Ok(())
+}
+
+fn load_invoice(program_id: &Pubkey, pda: &AccountInfo) -> Result<Invoice, ProgramError> {
+    if pda.owner != program_id {
+        return Err(InvoiceError::WrongOwner.into());
+    }
+
+    if pda.data_is_empty() {
+        return Err(InvoiceError::InvoiceNotFound.into());
+    }
+
+    let invoice = Invoice::unpack(&pda.data.borrow())?;
+    check_invoice_address(program_id, pda, &invoice)?;
+
+    Ok(invoice)
+}
+
+fn check_invoice_address(program_id: &Pubkey, pda: &AccountInfo, invoice: &Invoice) -> ProgramResult {
+    let seed_id = invoice.id.to_be_bytes();
+    let bump = [invoice.bump];
+    let seeds: &[&[u8]] = if invoice.legacy_address { &[&seed_id, &bump] } else { &[invoice.issuer.as_ref(), &seed_id, &bump] };
+
+    let pda_key = Pubkey::create_program_address(seeds, program_id).map_err(|_| InvoiceError::InvalidInvoiceAddress)?;
+    if *pda.key != pda_key {
+        return Err(InvoiceError::InvalidInvoiceAddress.into());
+    }
+
+    Ok(())
+}
+
+fn save_invoice(pda: &AccountInfo, invoice: &Invoice) -> ProgramResult {
+    invoice.pack(&mut pda.data.borrow_mut())
+}
+
+/// Writes the metadata after the invoice state, the account must already have the right size.
+fn save_invoice_metadata(pda: &AccountInfo, invoice: &Invoice, metadata: &InvoiceMetadata) -> ProgramResult {
+    if metadata.space()? == 0 {
+        return Ok(());
+    }
+
+    let mut data = pda.data.borrow_mut();
+    let mut tail = data.get_mut(invoice.space()?..).ok_or(ProgramError::AccountDataTooSmall)?;
+    metadata.serialize(&mut tail)?;
+
+    Ok(())
+}
+
+/// Checks the metadata limits and that line items, if any, add up to the invoice `amount`.
+fn check_metadata(metadata: &InvoiceMetadata, amount: u64) -> ProgramResult {
+    let memo_len = metadata.memo.as_ref().map_or(0, String::len);
+    if memo_len > MAX_MEMO_LEN || metadata.line_items.len() > MAX_LINE_ITEMS {
+        return Err(InvoiceError::InvalidMetadata.into());
+    }
+
+    if metadata.line_items.is_empty() {
+        return Ok(());
+    }
+
+    let total = metadata
+        .line_items
+        .iter()
+        .try_fold(0u64, |total, item| total.checked_add(item.total()?))
+        .ok_or(InvoiceError::LineItemsOverflow)?;
+    if total != amount {
+        return Err(InvoiceError::AmountMismatch.into());
+    }
+
+    Ok(())
+}
+
+fn load_escrow(invoice: &Invoice) -> Result<Escrow, ProgramError> {
+    match invoice.escrow {
+        None => Err(InvoiceError::NotEscrowInvoice.into()),
+        Some(escrow) if escrow.settled => Err(InvoiceError::EscrowSettled.into()),
+        Some(escrow) => Ok(escrow),
+    }
+}
+
+fn check_vault(program_id: &Pubkey, invoice_key: &Pubkey, escrow: &Escrow, vault: &AccountInfo) -> ProgramResult {
+    let vault_key = Pubkey::create_program_address(&[VAULT_SEED, invoice_key.as_ref(), &[escrow.vault_bump]], program_id)
+        .map_err(|_| InvoiceError::InvalidVault)?;
+    if *vault.key != vault_key {
+        return Err(InvoiceError::InvalidVault.into());
+    }
+
+    Ok(())
+}
+
+/// Checks that the splits fit in the invoice amount net of its protocol fee. Escrow invoices
+/// release their funds to the destination alone, so they can't be split.
+fn check_splits(invoice: &Invoice) -> ProgramResult {
+    if invoice.splits.is_empty() {
+        return Ok(());
+    }
+
+    if invoice.splits.len() > MAX_SPLITS || invoice.escrow.is_some() {
+        return Err(InvoiceError::InvalidSplits.into());
+    }
+
+    let net_amount = invoice.amount - invoice.fee;
+    let mut total: u64 = 0;
+    for split in &invoice.splits {
+        let valid = match split.share {
+            Share::Bps(bps) => bps > 0 && u64::from(bps) <= BPS_DENOMINATOR,
+            Share::Fixed(amount) => amount > 0,
+        };
+        if !valid {
+            return Err(InvoiceError::InvalidSplits.into());
+        }
+
+        total = total.checked_add(split.total(net_amount)).ok_or(InvoiceError::InvalidSplits)?;
+    }
+
+    if total > net_amount {
+        return Err(InvoiceError::InvalidSplits.into());
+    }
+
+    Ok(())
+}
+
+fn check_treasury(program_id: &Pubkey, config: &Config, treasury: &AccountInfo) -> ProgramResult {
+    let treasury_key = Pubkey::create_program_address(&[TREASURY_SEED, &[config.treasury_bump]], program_id)
+        .map_err(|_| InvoiceError::InvalidTreasury)?;
+    if *treasury.key != treasury_key {
+        return Err(InvoiceError::InvalidTreasury.into());
+    }
+
+    Ok(())
+}
+
+fn check_open(invoice: &Invoice) -> ProgramResult {
+    match invoice.status {
+        InvoiceStatus::Open | InvoiceStatus::PartiallyPaid => Ok(()),
+        InvoiceStatus::Paid => Err(InvoiceError::AlreadyPaid.into()),
+        InvoiceStatus::Cancelled => Err(InvoiceError::InvoiceCancelled.into()),
+        InvoiceStatus::Refunded | InvoiceStatus::PartiallyRefunded => Err(InvoiceError::InvoiceRefunded.into()),
+    }
+}
+
+fn check_deadlines(invoice: &Invoice) -> ProgramResult {
+    if invoice.due_at < 0 || invoice.expires_at < 0 {
+        return Err(InvoiceError::InvalidDeadline.into());
+    }
+
+    if invoice.due_at != 0 && invoice.expires_at != 0 && invoice.due_at > invoice.expires_at {
+        return Err(InvoiceError::InvalidDeadline.into());
+    }
+
+    Ok(())
+}
+
+fn check_admin(admin: &AccountInfo, config: &Config) -> ProgramResult {
+    if *admin.key != config.admin {
+        return Err(InvoiceError::InvalidAdmin.into());
+    }
+
+    if !admin.is_signer {
+        msg!("access denied. Admin isn't a transaction signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    Ok(())
+}
+
+fn load_config(program_id: &Pubkey, config: &AccountInfo) -> Result<Config, ProgramError> {
+    if config.owner != program_id || config.data_is_empty() {
+        return Err(InvoiceError::InvalidConfig.into());
+    }
+
+    let state = Config::unpack(&config.data.borrow())?;
+
+    let config_key = Pubkey::create_program_address(&[CONFIG_SEED, &[state.bump]], program_id)
+        .map_err(|_| InvoiceError::InvalidConfig)?;
+    if *config.key != config_key {
+        return Err(InvoiceError::InvalidConfig.into());
+    }
+
+    Ok(state)
+}
+
+fn load_merchant(program_id: &Pubkey, merchant_registry: &AccountInfo, merchant: &Pubkey) -> Result<Merchant, ProgramError> {
+    if merchant_registry.owner != program_id || merchant_registry.data_is_empty() {
+        return Err(InvoiceError::MerchantNotRegistered.into());
+    }
+
+    let state = Merchant::unpack(&merchant_registry.data.borrow())?;
+
+    let merchant_registry_key = Pubkey::create_program_address(&[MERCHANT_SEED, merchant.as_ref(), &[state.bump]], program_id)
+        .map_err(|_| InvoiceError::InvalidMerchantAccount)?;
+    if *merchant_registry.key != merchant_registry_key || state.merchant != *merchant {
+        return Err(InvoiceError::InvalidMerchantAccount.into());
+    }
+
+    Ok(state)
+}
+
+fn load_subscription(program_id: &Pubkey, subscription: &AccountInfo) -> Result<Subscription, ProgramError> {
+    if subscription.owner != program_id || subscription.data_is_empty() {
+        return Err(InvoiceError::InvalidSubscription.into());
+    }
+
+    let state = Subscription::unpack(&subscription.data.borrow())?;
+
+    let subscription_key = Pubkey::create_program_address(
+        &[SUBSCRIPTION_SEED, state.merchant.as_ref(), &state.id.to_be_bytes(), &[state.bump]],
+        program_id,
+    )
+    .map_err(|_| InvoiceError::InvalidSubscription)?;
+    if *subscription.key != subscription_key {
+        return Err(InvoiceError::InvalidSubscription.into());
+    }
+
+    Ok(state)
+}
+
+fn save_subscription(subscription: &AccountInfo, state: &Subscription) -> ProgramResult {
+    state.pack(&mut subscription.data.borrow_mut())
+}
+
+fn save_config(config: &AccountInfo, state: Config) -> ProgramResult {
+    state.pack(&mut config.data.borrow_mut())
+}
+
+/// Reads the upgrade authority of this program from its program data account.
+fn upgrade_authority(program_id: &Pubkey, program_data: &AccountInfo) -> Result<Option<Pubkey>, ProgramError> {
+    let (program_data_key, _) = Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    if *program_data.key != program_data_key || !bpf_loader_upgradeable::check_id(program_data.owner) {
+        msg!("invalid program data account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // Bincode layout of `UpgradeableLoaderState::ProgramData`:
+    // u32 variant (3), u64 slot, Option<Pubkey> upgrade authority
+    let data = program_data.data.borrow();
+    match data.get(..45) {
+        Some([3, 0, 0, 0, _, _, _, _, _, _, _, _, 0, ..]) => Ok(None),
+        Some([3, 0, 0, 0, _, _, _, _, _, _, _, _, 1, authority @ ..]) => {
+            Ok(Some(Pubkey::try_from(authority).map_err(|_| ProgramError::InvalidAccountData)?))
+        }
+        _ => Err(ProgramError::InvalidAccountData),
+    }
+}
+
+/// Program accounts needed to move the currency an invoice is denominated in.
+enum Currency<'a, 'b> {
+    Native {
+        system_program: &'b AccountInfo<'a>,
+    },
+    Token {
+        mint: &'b AccountInfo<'a>,
+        token_program: &'b AccountInfo<'a>,
+        decimals: u8,
+    },
+}
+
+impl<'a, 'b> Currency<'a, 'b> {
+    /// Validates the system program and, for token payments, takes the mint and token program
+    /// as the next two accounts.
+    fn from_accounts(
+        mint: Option<Pubkey>,
+        system_program: &'b AccountInfo<'a>,
+        accounts: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+    ) -> Result<Self, ProgramError> {
+        if !system_program::check_id(system_program.key) {
+            return Err(InvoiceError::InvalidSystemProgram.into());
+        }
+
+        let Some(expected_mint) = mint else {
+            return Ok(Self::Native { system_program });
+        };
+
+        let mint = next_account_info(accounts)?;
+        let token_program = next_account_info(accounts)?;
+
+        if spl_token_2022::check_spl_token_program_account(token_program.key).is_err() {
+            return Err(InvoiceError::InvalidTokenProgram.into());
+        }
+
+        if *mint.key != expected_mint || mint.owner != token_program.key {
+            return Err(InvoiceError::InvalidMint.into());
+        }
+
+        let decimals = {
+            let mint_data = mint.data.borrow();
+            let mint_state = StateWithExtensions::<Mint>::unpack(&mint_data)?;
+
+            // A transfer fee would leave the destination short of the amount recorded as paid, and
+            // hooks need extra accounts payments don't pass
+            let unsupported = |extension: &ExtensionType| matches!(extension, ExtensionType::TransferFeeConfig | ExtensionType::TransferHook);
+            if mint_state.get_extension_types()?.iter().any(unsupported) {
+                return Err(InvoiceError::UnsupportedMint.into());
+            }
+
+            mint_state.base.decimals
+        };
+
+        Ok(Self::Token { mint, token_program, decimals })
+    }
+
+    /// Creates the escrow vault: a program owned account holding lamports, or a token account
+    /// that is its own authority.
+    fn create_vault(
+        &self,
+        program_id: &Pubkey,
+        payer: &AccountInfo<'a>,
+        vault: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        sysvar_rent_program: &AccountInfo<'a>,
+        vault_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        match self {
+            Self::Native { .. } => {
+                create_pda_account(program_id, payer, vault, system_program, sysvar_rent_program, vault_seeds, 0)
+            }
+            Self::Token { mint, token_program, .. } => {
+                let space = {
+                    let mint_data = mint.data.borrow();
+                    let mint_state = StateWithExtensions::<Mint>::unpack(&mint_data)?;
+                    let extensions = ExtensionType::get_required_init_account_extensions(&mint_state.get_extension_types()?);
+                    ExtensionType::try_calculate_account_len::<Account>(&extensions)?
+                };
+
+                create_pda_account(token_program.key, payer, vault, system_program, sysvar_rent_program, vault_seeds, space)?;
+
+                invoke_signed(
+                    &spl_token_2022::instruction::initialize_account3(token_program.key, vault.key, mint.key, vault.key)?,
+                    &[vault.clone(), (*mint).clone(), (*token_program).clone()],
+                    &[],
+                )
+            }
+        }
+    }
+
+    /// Moves `amount` out of a program owned vault, signing for token transfers with `vault_seeds`.
+    fn transfer_from_vault(
+        &self,
+        vault: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        amount: u64,
+        vault_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        match self {
+            Self::Native { .. } => {
+                let vault_lamports = vault.lamports().checked_sub(amount).ok_or(ProgramError::InsufficientFunds)?;
+                let destination_lamports = destination.lamports().checked_add(amount).ok_or(ProgramError::ArithmeticOverflow)?;
+                **vault.try_borrow_mut_lamports()? = vault_lamports;
+                **destination.try_borrow_mut_lamports()? = destination_lamports;
+
+                Ok(())
+            }
+            Self::Token { .. } => self.transfer(vault, vault, destination, amount, &[vault_seeds]),
+        }
+    }
+
+    /// Closes an emptied escrow vault and moves its rent to `receiver`.
+    fn close_vault(&self, vault: &AccountInfo<'a>, receiver: &AccountInfo<'a>, vault_seeds: &[&[u8]]) -> ProgramResult {
+        match self {
+            Self::Native { .. } => close_account(vault, receiver),
+            Self::Token { token_program, .. } => invoke_signed(
+                &spl_token_2022::instruction::close_account(token_program.key, vault.key, receiver.key, vault.key, &[])?,
+                &[vault.clone(), receiver.clone(), vault.clone(), (*token_program).clone()],
+                &[vault_seeds],
+            ),
+        }
+    }
+
+    /// Checks that `account` is the associated token account of `wallet` for the invoice mint.
+    fn check_token_account(&self, wallet: &Pubkey, account: &AccountInfo) -> ProgramResult {
+        let Self::Token { mint, token_program, .. } = self else {
+            return Ok(());
+        };
+
+        let expected = get_associated_token_address_with_program_id(wallet, mint.key, token_program.key);
+        if *account.key != expected {
+            return Err(InvoiceError::InvalidTokenAccount.into());
+        }
+
+        Ok(())
+    }
+
+    /// Moves `amount` from `source` to `destination`. For lamports `authority` must be the
+    /// source itself, for tokens it's the owner or delegate of the source token account.
+    fn transfer(
+        &self,
+        authority: &AccountInfo<'a>,
+        source: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        amount: u64,
+        signer_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        match self {
+            Self::Native { system_program } => invoke_signed(
+                &system_instruction::transfer(source.key, destination.key, amount),
+                &[source.clone(), destination.clone(), (*system_program).clone()],
+                signer_seeds,
+            ),
+            Self::Token { mint, token_program, decimals } => invoke_signed(
+                &spl_token_2022::instruction::transfer_checked(
+                    token_program.key,
+                    source.key,
+                    mint.key,
+                    destination.key,
+                    authority.key,
+                    &[],
+                    amount,
+                    *decimals,
+                )?,
+                &[source.clone(), (*mint).clone(), destination.clone(), authority.clone(), (*token_program).clone()],
+                signer_seeds,
+            ),
+        }
+    }
+}
+
+/// Moves all lamports of a program owned account to `receiver` and hands it back to the system program.
+fn close_account(account: &AccountInfo, receiver: &AccountInfo) -> ProgramResult {
+    let lamports = receiver.lamports()
+        .checked_add(account.lamports())
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    **receiver.try_borrow_mut_lamports()? = lamports;
+    **account.try_borrow_mut_lamports()? = 0;
+
+    account.try_borrow_mut_data()?.fill(0);
+    account.assign(&system_program::id());
+
+    Ok(())
+}
+
+/// Creates a rent exempt account owned by `owner` at a PDA derived from `signer_seeds`.
+///
+/// A PDA anyone already sent lamports to can't be created with `create_account`, so it's topped
+/// up to the rent exempt minimum, then allocated and assigned instead.
+fn create_pda_account<'a>(
+    owner: &Pubkey,
+    payer: &AccountInfo<'a>,
+    pda: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    sysvar_rent_program: &AccountInfo<'a>,
+    signer_seeds: &[&[u8]],
+    space: usize,
+) -> ProgramResult {
+    let rent = Rent::from_account_info(sysvar_rent_program)?;
+    let minimum_balance = rent.minimum_balance(space);
+
+    if pda.lamports() == 0 {
+        return invoke_signed(
+            &system_instruction::create_account(
+                payer.key,
+                pda.key,
+                minimum_balance,
+                space as u64,
+                owner,
+            ),
+            &[payer.clone(), pda.clone(), system_program.clone()],
+            &[signer_seeds],
+        );
+    }
+
+    let top_up = minimum_balance.saturating_sub(pda.lamports());
+    if top_up > 0 {
+        invoke_signed(
+            &system_instruction::transfer(payer.key, pda.key, top_up),
+            &[payer.clone(), pda.clone(), system_program.clone()],
+            &[],
+        )?;
+    }
+
+    invoke_signed(
+        &system_instruction::allocate(pda.key, space as u64),
+        &[pda.clone(), system_program.clone()],
+        &[signer_seeds],
+    )?;
+
+    invoke_signed(
+        &system_instruction::assign(pda.key, owner),
+        &[pda.clone(), system_program.clone()],
+        &[signer_seeds],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{LineItem, Split};
+    use spl_token_2022::extension::{transfer_fee::TransferFeeConfig, transfer_hook::TransferHook, BaseStateWithExtensionsMut, StateWithExtensionsMut};
+
+    struct TestAccount {
+        key: Pubkey,
+        is_signer: bool,
+        is_writable: bool,
+        lamports: u64,
+        data: Vec<u8>,
+        owner: Pubkey,
+        executable: bool,
+    }
+
+    impl TestAccount {
+        fn new(key: Pubkey, lamports: u64, data: Vec<u8>, owner: Pubkey) -> Self {
+            Self { key, is_signer: false, is_writable: false, lamports, data, owner, executable: false }
+        }
+
+        fn wallet(lamports: u64) -> Self {
+            Self::new(Pubkey::new_unique(), lamports, vec![], system_program::id())
+        }
+
+        fn system_program() -> Self {
+            Self { executable: true, ..Self::new(system_program::id(), 1, vec![], system_program::id()) }
+        }
+
+        fn rent_sysvar() -> Self {
+            let rent = Rent::default();
+            let data = [
+                rent.lamports_per_byte_year.to_le_bytes().as_slice(),
+                rent.exemption_threshold.to_le_bytes().as_slice(),
+                &[rent.burn_percent],
+            ]
+            .concat();
+            Self::new(solana_program::sysvar::rent::id(), 1, data, solana_sdk_ids::sysvar::id())
+        }
+
+        fn clock_sysvar(slot: u64, unix_timestamp: i64) -> Self {
+            let data = [slot.to_le_bytes(), 0i64.to_le_bytes(), 0u64.to_le_bytes(), 0u64.to_le_bytes(), unix_timestamp.to_le_bytes()].concat();
+            Self::new(solana_program::sysvar::clock::id(), 1, data, solana_sdk_ids::sysvar::id())
+        }
+
+        fn signer(self) -> Self {
+            Self { is_signer: true, is_writable: true, ..self }
+        }
+
+        fn writable(self) -> Self {
+            Self { is_writable: true, ..self }
+        }
+
+        fn info(&mut self) -> AccountInfo<'_> {
+            AccountInfo::new(
+                &self.key,
+                self.is_signer,
+                self.is_writable,
+                &mut self.lamports,
+                &mut self.data,
+                &self.owner,
+                self.executable,
+                0,
+            )
+        }
+    }
+
+    fn packed<T: AccountState>(state: &T) -> Vec<u8> {
+        let mut data = vec![0; state.space().unwrap()];
+        state.pack(&mut data).unwrap();
+        data
+    }
+
+    fn invoice_pda(program_id: &Pubkey, invoice: &Invoice) -> TestAccount {
+        let key = Pubkey::create_program_address(&[invoice.issuer.as_ref(), &invoice.id.to_be_bytes(), &[invoice.bump]], program_id).unwrap();
+        TestAccount::new(key, 1_000, packed(invoice), *program_id).writable()
+    }
+
+    fn open_invoice(program_id: &Pubkey, issuer: &Pubkey, id: u128, amount: u64, destination: &Pubkey) -> Invoice {
+        let (_, bump) = Pubkey::find_program_address(&[issuer.as_ref(), &id.to_be_bytes()], program_id);
+        Invoice::open(id, *issuer, amount, destination.to_bytes(), bump)
+    }
+
+    fn escrow_invoice(program_id: &Pubkey, issuer: &Pubkey, destination: &Pubkey, payer: &Pubkey, arbiter: &Pubkey) -> (Invoice, TestAccount) {
+        let mut invoice = open_invoice(program_id, issuer, 11, 500, destination);
+        let pda_key = Pubkey::create_program_address(&[issuer.as_ref(), &invoice.id.to_be_bytes(), &[invoice.bump]], program_id).unwrap();
+        let (vault_key, vault_bump) = Pubkey::find_program_address(&[VAULT_SEED, pda_key.as_ref()], program_id);
+        invoice.escrow = Some(Escrow { arbiter: Some(*arbiter), timeout: 3_600, settled: false, vault_bump });
+        invoice.status = InvoiceStatus::Paid;
+        invoice.amount_paid = 500;
+        invoice.payer = *payer;
+        invoice.paid_at = 1_700_000_000;
+        (invoice, TestAccount::new(vault_key, 1_500, vec![], *program_id).writable())
+    }
+
+    fn merchant_pda(program_id: &Pubkey, merchant: &Pubkey) -> TestAccount {
+        let (key, bump) = Pubkey::find_program_address(&[MERCHANT_SEED, merchant.as_ref()], program_id);
+        let data = packed(&Merchant { merchant: *merchant, bump });
+        TestAccount::new(key, 1_000, data, *program_id)
+    }
+
+    fn mint(token_program: &Pubkey, decimals: u8) -> TestAccount {
+        let mut data = vec![0; 82];
+        data[44] = decimals;
+        data[45] = 1;
+        TestAccount::new(Pubkey::new_unique(), 1, data, *token_program)
+    }
+
+    fn mint_with_extension(extension: ExtensionType) -> TestAccount {
+        let mut data = vec![0; ExtensionType::try_calculate_account_len::<Mint>(&[extension]).unwrap()];
+        let mut state = StateWithExtensionsMut::<Mint>::unpack_uninitialized(&mut data).unwrap();
+        match extension {
+            ExtensionType::TransferFeeConfig => drop(state.init_extension::<TransferFeeConfig>(true).unwrap()),
+            ExtensionType::TransferHook => drop(state.init_extension::<TransferHook>(true).unwrap()),
+            _ => unreachable!("unexpected mint extension"),
+        }
+        state.base = Mint { decimals: 6, is_initialized: true, ..Mint::default() };
+        state.pack_base();
+        state.init_account_type().unwrap();
+        TestAccount::new(Pubkey::new_unique(), 1, data, spl_token_2022::id())
+    }
+
+    fn config_pda(program_id: &Pubkey, admin: &Pubkey, pending_admin: Option<Pubkey>) -> TestAccount {
+        let (key, bump) = Pubkey::find_program_address(&[CONFIG_SEED], program_id);
+        let (_, treasury_bump) = Pubkey::find_program_address(&[TREASURY_SEED], program_id);
+        let config = Config { admin: *admin, pending_admin: Some(Pubkey::default()), fee_bps: 0, fee_min: 0, treasury_bump, bump };
+        let mut data = packed(&config);
+        Config { pending_admin, ..config }.pack(&mut data).unwrap();
+        TestAccount::new(key, 1_000, data, *program_id).writable()
+    }
+
+    fn treasury_pda(program_id: &Pubkey) -> TestAccount {
+        let (key, _) = Pubkey::find_program_address(&[TREASURY_SEED], program_id);
+        TestAccount::new(key, 890_880, vec![], *program_id).writable()
+    }
+
+    fn subscription_pda(program_id: &Pubkey, merchant: &Pubkey, subscriber: &Pubkey, next_due_at: i64) -> TestAccount {
+        let id = 9;
+        let (key, bump) = Pubkey::find_program_address(&[SUBSCRIPTION_SEED, merchant.as_ref(), &u128::to_be_bytes(id)], program_id);
+        let subscription = Subscription {
+            id,
+            merchant: *merchant,
+            subscriber: *subscriber,
+            destination: *merchant,
+            mint: Pubkey::new_unique(),
+            amount: 1_000,
+            period: 2_592_000,
+            next_due_at,
+            cycle: 0,
+            bump,
+        };
+        TestAccount::new(key, 1_000, packed(&subscription), *program_id).writable()
+    }
+
+    fn load_test_config(program_id: &Pubkey, config: &AccountInfo) -> Config {
+        load_config(program_id, config).unwrap()
+    }
+
+    fn pay_invoice_data() -> Vec<u8> {
+        borsh::to_vec(&InstructionData::PayInvoice).unwrap()
+    }
+
+    fn create_invoice_args(id: u128, amount: u64, destination: &Pubkey) -> CreateInvoiceArgs {
+        CreateInvoiceArgs {
+            id,
+            amount,
+            destination: destination.to_bytes(),
+            rent_receiver: None,
+            mint: None,
+            due_at: None,
+            expires_at: None,
+            escrow: None,
+            splits: vec![],
+            metadata: InvoiceMetadata::default(),
+        }
+    }
+
+    fn create_invoice_data(id: u128, amount: u64, destination: &Pubkey) -> Vec<u8> {
+        borsh::to_vec(&InstructionData::CreateInvoice(Box::new(create_invoice_args(id, amount, destination)))).unwrap()
+    }
+
+    #[test]
+    fn pay_invoice_rejects_replay() {
+        let program_id = Pubkey::new_unique();
+        let mut sender = TestAccount::wallet(1_000_000).signer();
+        let mut destination = TestAccount::wallet(0).writable();
+        let mut pda = invoice_pda(&program_id, &open_invoice(&program_id, &Pubkey::new_unique(), 7, 500, &destination.key));
+        let mut system_program = TestAccount::system_program();
+        let mut clock = TestAccount::clock_sysvar(42, 1_700_000_000);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+
+        let accounts = [sender.info(), pda.info(), destination.info(), system_program.info(), clock.info(), config.info(), treasury.info()];
+
+        process_instruction(&program_id, &accounts, &pay_invoice_data()).unwrap();
+
+        let invoice = load_invoice(&program_id, &accounts[1]).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::Paid);
+        assert_eq!(invoice.payer, *accounts[0].key);
+        assert_eq!(invoice.paid_at, 1_700_000_000);
+        assert_eq!(invoice.paid_slot, 42);
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &pay_invoice_data()),
+            Err(InvoiceError::AlreadyPaid.into()),
+        );
+    }
+
+    #[test]
+    fn pay_invoice_rejects_invoice_paid_by_earlier_transaction() {
+        let program_id = Pubkey::new_unique();
+        let mut sender = TestAccount::wallet(1_000_000).signer();
+        let mut destination = TestAccount::wallet(0).writable();
+        let mut invoice = open_invoice(&program_id, &Pubkey::new_unique(), 8, 500, &destination.key);
+        invoice.status = InvoiceStatus::Paid;
+        let mut pda = invoice_pda(&program_id, &invoice);
+        let mut system_program = TestAccount::system_program();
+        let mut clock = TestAccount::clock_sysvar(0, 0);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+
+        let accounts = [sender.info(), pda.info(), destination.info(), system_program.info(), clock.info(), config.info(), treasury.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &pay_invoice_data()),
+            Err(InvoiceError::AlreadyPaid.into()),
+        );
+        assert_eq!(accounts[1].data.borrow().as_ref(), packed(&invoice).as_slice());
+    }
+
+    #[test]
+    fn pay_invoice_rejects_foreign_pda() {
+        let program_id = Pubkey::new_unique();
+        let mut sender = TestAccount::wallet(1_000_000).signer();
+        let mut destination = TestAccount::wallet(0).writable();
+        let mut pda = invoice_pda(&program_id, &open_invoice(&program_id, &Pubkey::new_unique(), 9, 500, &destination.key));
+        pda.owner = Pubkey::new_unique();
+        let mut system_program = TestAccount::system_program();
+        let mut clock = TestAccount::clock_sysvar(0, 0);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+
+        let accounts = [sender.info(), pda.info(), destination.info(), system_program.info(), clock.info(), config.info(), treasury.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &pay_invoice_data()),
+            Err(InvoiceError::WrongOwner.into()),
+        );
+    }
+
+    #[test]
+    fn pay_invoice_rejects_pda_not_derived_from_invoice_id() {
+        let program_id = Pubkey::new_unique();
+        let mut sender = TestAccount::wallet(1_000_000).signer();
+        let mut destination = TestAccount::wallet(0).writable();
+        let issuer = Pubkey::new_unique();
+        let mut pda = invoice_pda(&program_id, &open_invoice(&program_id, &issuer, 10, 500, &destination.key));
+        pda.data = packed(&open_invoice(&program_id, &issuer, 11, 500, &destination.key));
+        let mut system_program = TestAccount::system_program();
+        let mut clock = TestAccount::clock_sysvar(0, 0);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+
+        let accounts = [sender.info(), pda.info(), destination.info(), system_program.info(), clock.info(), config.info(), treasury.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &pay_invoice_data()),
+            Err(InvoiceError::InvalidInvoiceAddress.into()),
+        );
+    }
+
+    #[test]
+    fn create_invoice_rejects_pda_not_derived_from_invoice_id() {
+        let program_id = Pubkey::new_unique();
+        let mut merchant = TestAccount::wallet(1_000_000).signer();
+        let mut pda = TestAccount::wallet(0).writable();
+        let mut system_program = TestAccount::system_program();
+        let mut rent = TestAccount::rent_sysvar();
+        let mut merchant_registry = merchant_pda(&program_id, &merchant.key);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+
+        let accounts = [merchant.info(), pda.info(), system_program.info(), rent.info(), merchant_registry.info(), config.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &create_invoice_data(12, 500, &Pubkey::new_unique())),
+            Err(InvoiceError::InvalidInvoiceAddress.into()),
+        );
+    }
+
+    #[test]
+    fn create_invoice_rejects_zero_amount() {
+        let program_id = Pubkey::new_unique();
+        let mut merchant = TestAccount::wallet(1_000_000).signer();
+        let (pda_key, _) = Pubkey::find_program_address(&[merchant.key.as_ref(), &14u128.to_be_bytes()], &program_id);
+        let mut pda = TestAccount::new(pda_key, 0, vec![], system_program::id()).writable();
+        let mut system_program = TestAccount::system_program();
+        let mut rent = TestAccount::rent_sysvar();
+        let mut merchant_registry = merchant_pda(&program_id, &merchant.key);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+
+        let accounts = [merchant.info(), pda.info(), system_program.info(), rent.info(), merchant_registry.info(), config.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &create_invoice_data(14, 0, &Pubkey::new_unique())),
+            Err(InvoiceError::InvalidAmount.into()),
+        );
+    }
+
+    #[test]
+    fn create_invoice_rejects_existing_invoice_id() {
+        let program_id = Pubkey::new_unique();
+        let destination = Pubkey::new_unique();
+        let mut merchant = TestAccount::wallet(1_000_000).signer();
+        let mut pda = invoice_pda(&program_id, &open_invoice(&program_id, &merchant.key, 13, 500, &destination));
+        let mut system_program = TestAccount::system_program();
+        let mut rent = TestAccount::rent_sysvar();
+        let mut merchant_registry = merchant_pda(&program_id, &merchant.key);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+
+        let accounts = [merchant.info(), pda.info(), system_program.info(), rent.info(), merchant_registry.info(), config.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &create_invoice_data(13, 900, &destination)),
+            Err(InvoiceError::AlreadyExists.into()),
+        );
+    }
+
+    #[test]
+    fn create_invoice_rejects_unregistered_merchant() {
+        let program_id = Pubkey::new_unique();
+        let destination = Pubkey::new_unique();
+        let mut merchant = TestAccount::wallet(1_000_000).signer();
+        let mut pda = TestAccount::wallet(0).writable();
+        pda.key = Pubkey::find_program_address(&[merchant.key.as_ref(), &14u128.to_be_bytes()], &program_id).0;
+        let mut system_program = TestAccount::system_program();
+        let mut rent = TestAccount::rent_sysvar();
+        let mut merchant_registry = TestAccount::wallet(0);
+        merchant_registry.key = Pubkey::find_program_address(&[MERCHANT_SEED, merchant.key.as_ref()], &program_id).0;
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+
+        let accounts = [merchant.info(), pda.info(), system_program.info(), rent.info(), merchant_registry.info(), config.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &create_invoice_data(14, 500, &destination)),
+            Err(InvoiceError::MerchantNotRegistered.into()),
+        );
+    }
+
+    #[test]
+    fn create_invoice_rejects_registry_entry_of_another_merchant() {
+        let program_id = Pubkey::new_unique();
+        let destination = Pubkey::new_unique();
+        let mut merchant = TestAccount::wallet(1_000_000).signer();
+        let mut pda = TestAccount::wallet(0).writable();
+        pda.key = Pubkey::find_program_address(&[merchant.key.as_ref(), &15u128.to_be_bytes()], &program_id).0;
+        let mut system_program = TestAccount::system_program();
+        let mut rent = TestAccount::rent_sysvar();
+        let mut merchant_registry = merchant_pda(&program_id, &Pubkey::new_unique());
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+
+        let accounts = [merchant.info(), pda.info(), system_program.info(), rent.info(), merchant_registry.info(), config.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &create_invoice_data(15, 500, &destination)),
+            Err(InvoiceError::InvalidMerchantAccount.into()),
+        );
+    }
+
+    #[test]
+    fn register_merchant_requires_admin() {
+        let program_id = Pubkey::new_unique();
+        let merchant = Pubkey::new_unique();
+        let mut admin = TestAccount::wallet(1_000_000).signer();
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut merchant_registry = TestAccount::wallet(0).writable();
+        merchant_registry.key = Pubkey::find_program_address(&[MERCHANT_SEED, merchant.as_ref()], &program_id).0;
+        let mut system_program = TestAccount::system_program();
+        let mut rent = TestAccount::rent_sysvar();
+
+        let accounts = [admin.info(), config.info(), merchant_registry.info(), system_program.info(), rent.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &borsh::to_vec(&InstructionData::RegisterMerchant { merchant }).unwrap()),
+            Err(InvoiceError::InvalidAdmin.into()),
+        );
+    }
+
+    #[test]
+    fn admin_rotation_takes_effect_after_acceptance() {
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::wallet(0).signer();
+        let mut new_admin = TestAccount::wallet(0).signer();
+        let mut config = config_pda(&program_id, &admin.key, None);
+
+        let propose = borsh::to_vec(&InstructionData::ProposeAdmin { admin: new_admin.key }).unwrap();
+        let accept = borsh::to_vec(&InstructionData::AcceptAdmin).unwrap();
+
+        let admin_key = admin.key;
+        let new_admin_key = new_admin.key;
+        let admin = admin.info();
+        let new_admin = new_admin.info();
+        let config = config.info();
+
+        process_instruction(&program_id, &[admin.clone(), config.clone()], &propose).unwrap();
+        assert_eq!(load_test_config(&program_id, &config).admin, admin_key);
+        assert_eq!(load_test_config(&program_id, &config).pending_admin, Some(new_admin_key));
+
+        assert_eq!(
+            process_instruction(&program_id, &[admin.clone(), config.clone()], &accept),
+            Err(InvoiceError::InvalidAdmin.into()),
+        );
+
+        process_instruction(&program_id, &[new_admin.clone(), config.clone()], &accept).unwrap();
+        assert_eq!(load_test_config(&program_id, &config).admin, new_admin_key);
+        assert_eq!(load_test_config(&program_id, &config).pending_admin, None);
+
+        assert_eq!(
+            process_instruction(&program_id, &[admin, config], &propose),
+            Err(InvoiceError::InvalidAdmin.into()),
+        );
+    }
+
+    #[test]
+    fn accept_admin_requires_a_proposal() {
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::wallet(0).signer();
+        let mut config = config_pda(&program_id, &admin.key, None);
+
+        let accounts = [admin.info(), config.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &borsh::to_vec(&InstructionData::AcceptAdmin).unwrap()),
+            Err(InvoiceError::NoPendingAdmin.into()),
+        );
+    }
+
+    #[test]
+    fn set_admin_replaces_admin_and_clears_proposal() {
+        let program_id = Pubkey::new_unique();
+        let new_admin = Pubkey::new_unique();
+        let mut admin = TestAccount::wallet(0).signer();
+        let mut config = config_pda(&program_id, &admin.key, Some(Pubkey::new_unique()));
+
+        let accounts = [admin.info(), config.info()];
+
+        process_instruction(&program_id, &accounts, &borsh::to_vec(&InstructionData::SetAdmin { admin: new_admin }).unwrap()).unwrap();
+
+        let config = load_test_config(&program_id, &accounts[1]);
+        assert_eq!(config.admin, new_admin);
+        assert_eq!(config.pending_admin, None);
+    }
+
+    #[test]
+    fn initialize_config_requires_upgrade_authority() {
+        let program_id = Pubkey::new_unique();
+        let upgrade_authority = Pubkey::new_unique();
+        let mut payer = TestAccount::wallet(1_000_000).signer();
+        let mut config = TestAccount::wallet(0).writable();
+        config.key = Pubkey::find_program_address(&[CONFIG_SEED], &program_id).0;
+        let program_data_key = Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id()).0;
+        let program_data = [[3, 0, 0, 0].as_slice(), &[0; 8], &[1], upgrade_authority.as_ref()].concat();
+        let mut program_data = TestAccount::new(program_data_key, 1, program_data, bpf_loader_upgradeable::id());
+        let mut system_program = TestAccount::system_program();
+        let mut rent = TestAccount::rent_sysvar();
+        let mut treasury = TestAccount::wallet(0).writable();
+        treasury.key = Pubkey::find_program_address(&[TREASURY_SEED], &program_id).0;
+
+        let accounts = [payer.info(), config.info(), program_data.info(), system_program.info(), rent.info(), treasury.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &borsh::to_vec(&InstructionData::InitializeConfig { admin: upgrade_authority }).unwrap()),
+            Err(InvoiceError::InvalidAdmin.into()),
+        );
+    }
+
+    #[test]
+    fn cancelled_invoice_cannot_be_paid() {
+        let program_id = Pubkey::new_unique();
+        let mut issuer = TestAccount::wallet(0).signer();
+        let mut sender = TestAccount::wallet(1_000_000).signer();
+        let mut destination = TestAccount::wallet(0).writable();
+        let mut pda = invoice_pda(&program_id, &open_invoice(&program_id, &issuer.key, 16, 500, &destination.key));
+        let mut system_program = TestAccount::system_program();
+        let mut clock = TestAccount::clock_sysvar(0, 0);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+
+        let issuer = issuer.info();
+        let pda = pda.info();
+        let cancel = borsh::to_vec(&InstructionData::CancelInvoice).unwrap();
+
+        process_instruction(&program_id, &[issuer.clone(), pda.clone()], &cancel).unwrap();
+        assert_eq!(load_invoice(&program_id, &pda).unwrap().status, InvoiceStatus::Cancelled);
+
+        assert_eq!(
+            process_instruction(&program_id, &[issuer, pda.clone()], &cancel),
+            Err(InvoiceError::InvoiceCancelled.into()),
+        );
+        assert_eq!(
+            process_instruction(&program_id, &[sender.info(), pda, destination.info(), system_program.info(), clock.info(), config.info(), treasury.info()], &pay_invoice_data()),
+            Err(InvoiceError::InvoiceCancelled.into()),
+        );
+    }
+
+    #[test]
+    fn cancel_invoice_requires_issuer() {
+        let program_id = Pubkey::new_unique();
+        let mut other_merchant = TestAccount::wallet(0).signer();
+        let mut pda = invoice_pda(&program_id, &open_invoice(&program_id, &Pubkey::new_unique(), 17, 500, &Pubkey::new_unique()));
+
+        let accounts = [other_merchant.info(), pda.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &borsh::to_vec(&InstructionData::CancelInvoice).unwrap()),
+            Err(InvoiceError::InvalidIssuer.into()),
+        );
+        assert_eq!(load_invoice(&program_id, &accounts[1]).unwrap().status, InvoiceStatus::Open);
+    }
+
+    #[test]
+    fn paid_invoice_cannot_be_cancelled() {
+        let program_id = Pubkey::new_unique();
+        let mut issuer = TestAccount::wallet(0).signer();
+        let mut invoice = open_invoice(&program_id, &issuer.key, 18, 500, &Pubkey::new_unique());
+        invoice.status = InvoiceStatus::Paid;
+        let mut pda = invoice_pda(&program_id, &invoice);
+
+        let accounts = [issuer.info(), pda.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &borsh::to_vec(&InstructionData::CancelInvoice).unwrap()),
+            Err(InvoiceError::AlreadyPaid.into()),
+        );
+    }
+
+    #[test]
+    fn close_invoice_returns_rent_to_receiver() {
+        let program_id = Pubkey::new_unique();
+        let mut issuer = TestAccount::wallet(0).signer();
+        let mut rent_receiver = TestAccount::wallet(10).writable();
+        let mut invoice = open_invoice(&program_id, &issuer.key, 19, 500, &Pubkey::new_unique());
+        invoice.status = InvoiceStatus::Cancelled;
+        invoice.rent_receiver = rent_receiver.key;
+        let mut pda = invoice_pda(&program_id, &invoice);
+
+        let accounts = [issuer.info(), pda.info(), rent_receiver.info()];
+
+        process_instruction(&program_id, &accounts, &borsh::to_vec(&InstructionData::CloseInvoice).unwrap()).unwrap();
+
+        assert_eq!(accounts[1].lamports(), 0);
+        assert_eq!(accounts[2].lamports(), 1_010);
+        assert!(accounts[1].data.borrow().iter().all(|byte| *byte == 0));
+        assert!(system_program::check_id(accounts[1].owner));
+    }
+
+    #[test]
+    fn close_invoice_rejects_open_invoice() {
+        let program_id = Pubkey::new_unique();
+        let mut issuer = TestAccount::wallet(0).signer();
+        let mut pda = invoice_pda(&program_id, &open_invoice(&program_id, &issuer.key, 20, 500, &Pubkey::new_unique()));
+        let mut rent_receiver = TestAccount::new(issuer.key, 0, vec![], system_program::id()).writable();
+
+        let accounts = [issuer.info(), pda.info(), rent_receiver.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &borsh::to_vec(&InstructionData::CloseInvoice).unwrap()),
+            Err(InvoiceError::InvoiceStillOpen.into()),
+        );
+        assert_eq!(accounts[1].lamports(), 1_000);
+    }
+
+    #[test]
+    fn close_invoice_rejects_other_rent_receiver() {
+        let program_id = Pubkey::new_unique();
+        let mut issuer = TestAccount::wallet(0).signer();
+        let mut invoice = open_invoice(&program_id, &issuer.key, 21, 500, &Pubkey::new_unique());
+        invoice.status = InvoiceStatus::Paid;
+        let mut pda = invoice_pda(&program_id, &invoice);
+        let mut rent_receiver = TestAccount::wallet(0).writable();
+
+        let accounts = [issuer.info(), pda.info(), rent_receiver.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &borsh::to_vec(&InstructionData::CloseInvoice).unwrap()),
+            Err(InvoiceError::InvalidRentReceiver.into()),
+        );
+    }
+
+    #[test]
+    fn pay_invoice_accepts_destination_and_treasury_atas() {
+        let program_id = Pubkey::new_unique();
+        let token_program_id = spl_token_2022::id();
+        let mut sender = TestAccount::wallet(1_000_000).signer();
+        let mut destination = TestAccount::wallet(0);
+        let mut system_program = TestAccount::system_program();
+        let mut clock = TestAccount::clock_sysvar(0, 0);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+        let mut mint = mint(&token_program_id, 6);
+        let mut token_program = TestAccount { executable: true, ..TestAccount::new(token_program_id, 1, vec![], bpf_loader_upgradeable::id()) };
+        let mut sender_token_account = TestAccount::new(Pubkey::new_unique(), 1, vec![], token_program_id).writable();
+        let ata = get_associated_token_address_with_program_id(&destination.key, &mint.key, &token_program_id);
+        let mut destination_token_account = TestAccount::new(ata, 1, vec![], token_program_id).writable();
+        let treasury_ata = get_associated_token_address_with_program_id(&treasury.key, &mint.key, &token_program_id);
+        let mut treasury_token_account = TestAccount::new(treasury_ata, 1, vec![], token_program_id).writable();
+        let mut invoice = open_invoice(&program_id, &Pubkey::new_unique(), 22, 500, &destination.key);
+        invoice.mint = Some(mint.key);
+        let mut pda = invoice_pda(&program_id, &invoice);
+
+        let accounts = [
+            sender.info(),
+            pda.info(),
+            destination.info(),
+            system_program.info(),
+            clock.info(),
+            config.info(),
+            treasury.info(),
+            mint.info(),
+            token_program.info(),
+            sender_token_account.info(),
+            destination_token_account.info(),
+            treasury_token_account.info(),
+        ];
+
+        process_instruction(&program_id, &accounts, &pay_invoice_data()).unwrap();
+
+        assert_eq!(load_invoice(&program_id, &accounts[1]).unwrap().status, InvoiceStatus::Paid);
+    }
+
+    #[test]
+    fn pay_invoice_rejects_token_account_other_than_destination_ata() {
+        let program_id = Pubkey::new_unique();
+        let token_program_id = spl_token_2022::id();
+        let mut sender = TestAccount::wallet(1_000_000).signer();
+        let mut destination = TestAccount::wallet(0);
+        let mut system_program = TestAccount::system_program();
+        let mut clock = TestAccount::clock_sysvar(0, 0);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+        let mut mint = mint(&token_program_id, 6);
+        let mut token_program = TestAccount { executable: true, ..TestAccount::new(token_program_id, 1, vec![], bpf_loader_upgradeable::id()) };
+        let mut sender_token_account = TestAccount::new(Pubkey::new_unique(), 1, vec![], token_program_id).writable();
+        let mut attacker_token_account = TestAccount::new(Pubkey::new_unique(), 1, vec![], token_program_id).writable();
+        let mut treasury_token_account = TestAccount::new(Pubkey::new_unique(), 1, vec![], token_program_id).writable();
+        let mut invoice = open_invoice(&program_id, &Pubkey::new_unique(), 23, 500, &destination.key);
+        invoice.mint = Some(mint.key);
+        let mut pda = invoice_pda(&program_id, &invoice);
+
+        let accounts = [
+            sender.info(),
+            pda.info(),
+            destination.info(),
+            system_program.info(),
+            clock.info(),
+            config.info(),
+            treasury.info(),
+            mint.info(),
+            token_program.info(),
+            sender_token_account.info(),
+            attacker_token_account.info(),
+            treasury_token_account.info(),
+        ];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &pay_invoice_data()),
+            Err(InvoiceError::InvalidTokenAccount.into()),
+        );
+    }
+
+    #[test]
+    fn pay_invoice_rejects_mint_of_other_token_program() {
+        let program_id = Pubkey::new_unique();
+        let mut sender = TestAccount::wallet(1_000_000).signer();
+        let mut destination = TestAccount::wallet(0);
+        let mut system_program = TestAccount::system_program();
+        let mut clock = TestAccount::clock_sysvar(0, 0);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+        let mut mint = mint(&spl_token_2022::id(), 6);
+        let mut token_program = TestAccount { executable: true, ..TestAccount::new(Pubkey::from_str_const("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA"), 1, vec![], bpf_loader_upgradeable::id()) };
+        let mut sender_token_account = TestAccount::new(Pubkey::new_unique(), 1, vec![], spl_token_2022::id()).writable();
+        let mut destination_token_account = TestAccount::new(Pubkey::new_unique(), 1, vec![], spl_token_2022::id()).writable();
+        let mut treasury_token_account = TestAccount::new(Pubkey::new_unique(), 1, vec![], spl_token_2022::id()).writable();
+        let mut invoice = open_invoice(&program_id, &Pubkey::new_unique(), 24, 500, &destination.key);
+        invoice.mint = Some(mint.key);
+        let mut pda = invoice_pda(&program_id, &invoice);
+
+        let accounts = [
+            sender.info(),
+            pda.info(),
+            destination.info(),
+            system_program.info(),
+            clock.info(),
+            config.info(),
+            treasury.info(),
+            mint.info(),
+            token_program.info(),
+            sender_token_account.info(),
+            destination_token_account.info(),
+            treasury_token_account.info(),
+        ];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &pay_invoice_data()),
+            Err(InvoiceError::InvalidMint.into()),
+        );
+    }
+
+    #[test]
+    fn pay_invoice_rejects_mints_with_transfer_fee_or_hook() {
+        for extension in [ExtensionType::TransferFeeConfig, ExtensionType::TransferHook] {
+            let program_id = Pubkey::new_unique();
+            let token_program_id = spl_token_2022::id();
+            let mut sender = TestAccount::wallet(1_000_000).signer();
+            let mut destination = TestAccount::wallet(0);
+            let mut system_program = TestAccount::system_program();
+            let mut clock = TestAccount::clock_sysvar(0, 0);
+            let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+            let mut treasury = treasury_pda(&program_id);
+            let mut mint = mint_with_extension(extension);
+            let mut token_program = TestAccount { executable: true, ..TestAccount::new(token_program_id, 1, vec![], bpf_loader_upgradeable::id()) };
+            let mut invoice = open_invoice(&program_id, &Pubkey::new_unique(), 25, 500, &destination.key);
+            invoice.mint = Some(mint.key);
+            let mut pda = invoice_pda(&program_id, &invoice);
+
+            let accounts = [
+                sender.info(),
+                pda.info(),
+                destination.info(),
+                system_program.info(),
+                clock.info(),
+                config.info(),
+                treasury.info(),
+                mint.info(),
+                token_program.info(),
+            ];
+
+            assert_eq!(
+                process_instruction(&program_id, &accounts, &pay_invoice_data()),
+                Err(InvoiceError::UnsupportedMint.into()),
+            );
+        }
+    }
+
+    #[test]
+    fn partial_payments_settle_invoice_once_fully_covered() {
+        let program_id = Pubkey::new_unique();
+        let mut sender = TestAccount::wallet(1_000_000).signer();
+        let mut destination = TestAccount::wallet(0).writable();
+        let mut pda = invoice_pda(&program_id, &open_invoice(&program_id, &Pubkey::new_unique(), 25, 500, &destination.key));
+        let mut system_program = TestAccount::system_program();
+        let mut clock = TestAccount::clock_sysvar(0, 0);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+
+        let accounts = [sender.info(), pda.info(), destination.info(), system_program.info(), clock.info(), config.info(), treasury.info()];
+        let pay_partial = |amount| borsh::to_vec(&InstructionData::PayInvoicePartial { amount }).unwrap();
+
+        process_instruction(&program_id, &accounts, &pay_partial(200)).unwrap();
+        let invoice = load_invoice(&program_id, &accounts[1]).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::PartiallyPaid);
+        assert_eq!(invoice.outstanding(), 300);
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &pay_partial(301)),
+            Err(InvoiceError::Overpayment.into()),
+        );
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &pay_partial(0)),
+            Err(InvoiceError::InvalidAmount.into()),
+        );
+
+        process_instruction(&program_id, &accounts, &pay_invoice_data()).unwrap();
+        let invoice = load_invoice(&program_id, &accounts[1]).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::Paid);
+        assert_eq!(invoice.amount_paid, 500);
+        assert_eq!(invoice.outstanding(), 0);
+    }
+
+    #[test]
+    fn partial_payments_must_come_from_the_first_payer() {
+        let program_id = Pubkey::new_unique();
+        let mut sender = TestAccount::wallet(1_000_000).signer();
+        let mut other_sender = TestAccount::wallet(1_000_000).signer();
+        let mut destination = TestAccount::wallet(0).writable();
+        let mut pda = invoice_pda(&program_id, &open_invoice(&program_id, &Pubkey::new_unique(), 35, 500, &destination.key));
+        let mut system_program = TestAccount::system_program();
+        let mut clock = TestAccount::clock_sysvar(0, 0);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+        let pay_partial = borsh::to_vec(&InstructionData::PayInvoicePartial { amount: 200 }).unwrap();
+
+        let accounts = [sender.info(), pda.info(), destination.info(), system_program.info(), clock.info(), config.info(), treasury.info()];
+        process_instruction(&program_id, &accounts, &pay_partial).unwrap();
+
+        let mut accounts = accounts;
+        accounts[0] = other_sender.info();
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &pay_invoice_data()),
+            Err(InvoiceError::PayerMismatch.into()),
+        );
+        assert_eq!(load_invoice(&program_id, &accounts[1]).unwrap().amount_paid, 200);
+    }
+
+    #[test]
+    fn partially_paid_invoice_cannot_be_cancelled() {
+        let program_id = Pubkey::new_unique();
+        let mut issuer = TestAccount::wallet(0).signer();
+        let mut invoice = open_invoice(&program_id, &issuer.key, 26, 500, &Pubkey::new_unique());
+        invoice.amount_paid = 100;
+        invoice.status = InvoiceStatus::PartiallyPaid;
+        let mut pda = invoice_pda(&program_id, &invoice);
+
+        let accounts = [issuer.info(), pda.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &borsh::to_vec(&InstructionData::CancelInvoice).unwrap()),
+            Err(InvoiceError::InvoicePartiallyPaid.into()),
+        );
+    }
+
+    #[test]
+    fn pay_invoice_rejects_expired_invoice_and_flags_late_payment() {
+        let program_id = Pubkey::new_unique();
+        let mut sender = TestAccount::wallet(1_000_000).signer();
+        let mut destination = TestAccount::wallet(0).writable();
+        let mut invoice = open_invoice(&program_id, &Pubkey::new_unique(), 27, 500, &destination.key);
+        invoice.due_at = 1_000;
+        invoice.expires_at = 2_000;
+        let mut pda = invoice_pda(&program_id, &invoice);
+        let mut system_program = TestAccount::system_program();
+        let mut expired_clock = TestAccount::clock_sysvar(0, 2_001);
+        let mut late_clock = TestAccount::clock_sysvar(0, 1_500);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+
+        let sender = sender.info();
+        let pda = pda.info();
+        let destination = destination.info();
+        let system_program = system_program.info();
+        let config = config.info();
+        let treasury = treasury.info();
+
+        assert_eq!(
+            process_instruction(
+                &program_id,
+                &[sender.clone(), pda.clone(), destination.clone(), system_program.clone(), expired_clock.info(), config.clone(), treasury.clone()],
+                &pay_invoice_data(),
+            ),
+            Err(InvoiceError::InvoiceExpired.into()),
+        );
+
+        process_instruction(&program_id, &[sender, pda.clone(), destination, system_program, late_clock.info(), config, treasury], &pay_invoice_data()).unwrap();
+
+        let invoice = load_invoice(&program_id, &pda).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::Paid);
+        assert!(invoice.late);
+    }
+
+    #[test]
+    fn extend_invoice_only_moves_deadlines_forward() {
+        let program_id = Pubkey::new_unique();
+        let mut issuer = TestAccount::wallet(0).signer();
+        let mut invoice = open_invoice(&program_id, &issuer.key, 28, 500, &Pubkey::new_unique());
+        invoice.due_at = 1_000;
+        invoice.expires_at = 2_000;
+        let mut pda = invoice_pda(&program_id, &invoice);
+
+        let accounts = [issuer.info(), pda.info()];
+        let extend = |due_at, expires_at| borsh::to_vec(&InstructionData::ExtendInvoice { due_at, expires_at }).unwrap();
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &extend(Some(999), None)),
+            Err(InvoiceError::InvalidDeadline.into()),
+        );
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &extend(Some(2_500), None)),
+            Err(InvoiceError::InvalidDeadline.into()),
+        );
+
+        process_instruction(&program_id, &accounts, &extend(Some(2_500), Some(3_000))).unwrap();
+
+        let invoice = load_invoice(&program_id, &accounts[1]).unwrap();
+        assert_eq!(invoice.due_at, 2_500);
+        assert_eq!(invoice.expires_at, 3_000);
+    }
+
+    #[test]
+    fn refund_invoice_returns_payment_to_payer() {
+        let program_id = Pubkey::new_unique();
+        let mut destination = TestAccount::wallet(1_000).signer();
+        let mut payer = TestAccount::wallet(0).writable();
+        let mut invoice = open_invoice(&program_id, &Pubkey::new_unique(), 29, 500, &destination.key);
+        invoice.amount_paid = 500;
+        invoice.amount_received = 500;
+        invoice.status = InvoiceStatus::Paid;
+        invoice.payer = payer.key;
+        let mut pda = invoice_pda(&program_id, &invoice);
+        let mut system_program = TestAccount::system_program();
+
+        let accounts = [destination.info(), pda.info(), payer.info(), system_program.info()];
+        let refund = |amount| borsh::to_vec(&InstructionData::RefundInvoice { amount }).unwrap();
+
+        process_instruction(&program_id, &accounts, &refund(200)).unwrap();
+        let invoice = load_invoice(&program_id, &accounts[1]).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::PartiallyRefunded);
+        assert_eq!(invoice.amount_refunded, 200);
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &refund(301)),
+            Err(InvoiceError::RefundExceedsPaid.into()),
+        );
+
+        process_instruction(&program_id, &accounts, &refund(300)).unwrap();
+        let invoice = load_invoice(&program_id, &accounts[1]).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::Refunded);
+        assert_eq!(invoice.refundable(), 0);
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &refund(1)),
+            Err(InvoiceError::InvoiceRefunded.into()),
+        );
+    }
+
+    #[test]
+    fn refund_invoice_keeps_fee_and_splits() {
+        let program_id = Pubkey::new_unique();
+        let mut sender = TestAccount::wallet(1_000_000).signer();
+        let mut destination = TestAccount::wallet(0).signer();
+        let mut platform = TestAccount::wallet(0).writable();
+        let mut invoice = open_invoice(&program_id, &Pubkey::new_unique(), 34, 1_000, &destination.key);
+        invoice.fee = 20;
+        invoice.splits = vec![Split { recipient: platform.key, share: Share::Bps(1_000) }];
+        let mut pda = invoice_pda(&program_id, &invoice);
+        let mut system_program = TestAccount::system_program();
+        let mut clock = TestAccount::clock_sysvar(42, 1_700_000_000);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+
+        let accounts = [sender.info(), pda.info(), destination.info(), system_program.info(), clock.info(), config.info(), treasury.info(), platform.info()];
+        process_instruction(&program_id, &accounts, &pay_invoice_data()).unwrap();
+
+        // 20 go to the treasury and 98 to the platform, the destination receives 882
+        let invoice = load_invoice(&program_id, &accounts[1]).unwrap();
+        assert_eq!(invoice.amount_received, 882);
+
+        let refund = |amount| borsh::to_vec(&InstructionData::RefundInvoice { amount }).unwrap();
+        let accounts = [accounts[2].clone(), accounts[1].clone(), accounts[0].clone(), accounts[3].clone()];
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &refund(883)),
+            Err(InvoiceError::RefundExceedsPaid.into()),
+        );
+
+        process_instruction(&program_id, &accounts, &refund(882)).unwrap();
+        let invoice = load_invoice(&program_id, &accounts[1]).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::Refunded);
+        assert_eq!(invoice.refundable(), 118);
+    }
+
+    #[test]
+    fn refund_invoice_rejects_account_other_than_payer() {
+        let program_id = Pubkey::new_unique();
+        let mut destination = TestAccount::wallet(1_000).signer();
+        let mut attacker = TestAccount::wallet(0).writable();
+        let mut invoice = open_invoice(&program_id, &Pubkey::new_unique(), 30, 500, &destination.key);
+        invoice.amount_paid = 500;
+        invoice.status = InvoiceStatus::Paid;
+        invoice.payer = Pubkey::new_unique();
+        let mut pda = invoice_pda(&program_id, &invoice);
+        let mut system_program = TestAccount::system_program();
+
+        let accounts = [destination.info(), pda.info(), attacker.info(), system_program.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &borsh::to_vec(&InstructionData::RefundInvoice { amount: 500 }).unwrap()),
+            Err(InvoiceError::PayerMismatch.into()),
+        );
+    }
+
+    #[test]
+    fn refund_invoice_rejects_unpaid_invoice() {
+        let program_id = Pubkey::new_unique();
+        let mut destination = TestAccount::wallet(1_000).signer();
+        let mut payer = TestAccount::wallet(0).writable();
+        let mut pda = invoice_pda(&program_id, &open_invoice(&program_id, &Pubkey::new_unique(), 31, 500, &destination.key));
+        let mut system_program = TestAccount::system_program();
+
+        let accounts = [destination.info(), pda.info(), payer.info(), system_program.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &borsh::to_vec(&InstructionData::RefundInvoice { amount: 500 }).unwrap()),
+            Err(InvoiceError::InvoiceNotPaid.into()),
+        );
+    }
+
+    #[test]
+    fn pay_invoice_escrow_funds_vault() {
+        let program_id = Pubkey::new_unique();
+        let issuer = Pubkey::new_unique();
+        let destination = Pubkey::new_unique();
+        let (mut invoice, mut vault) = escrow_invoice(&program_id, &issuer, &destination, &Pubkey::default(), &Pubkey::new_unique());
+        invoice.status = InvoiceStatus::Open;
+        invoice.amount_paid = 0;
+        invoice.paid_at = 0;
+        let mut sender = TestAccount::wallet(1_000_000).signer();
+        let mut wallet = TestAccount::new(destination, 0, vec![], system_program::id()).writable();
+        let mut pda = invoice_pda(&program_id, &invoice);
+        let mut system_program = TestAccount::system_program();
+        let mut clock = TestAccount::clock_sysvar(42, 1_700_000_000);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+
+        let accounts = [sender.info(), pda.info(), wallet.info(), system_program.info(), clock.info(), config.info(), treasury.info()];
+        let err = process_instruction(&program_id, &accounts, &pay_invoice_data()).unwrap_err();
+        assert_eq!(err, InvoiceError::InvalidVault.into());
+
+        let accounts = [sender.info(), pda.info(), vault.info(), system_program.info(), clock.info(), config.info(), treasury.info()];
+        process_instruction(&program_id, &accounts, &pay_invoice_data()).unwrap();
+
+        let invoice = load_invoice(&program_id, &accounts[1]).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::Paid);
+        assert!(!invoice.escrow.unwrap().settled);
+    }
+
+    #[test]
+    fn release_escrow_pays_destination() {
+        let program_id = Pubkey::new_unique();
+        let payer = Pubkey::new_unique();
+        let destination = Pubkey::new_unique();
+        let (invoice, mut vault) = escrow_invoice(&program_id, &Pubkey::new_unique(), &destination, &payer, &Pubkey::new_unique());
+        let mut authority = TestAccount::new(payer, 0, vec![], system_program::id()).signer();
+        let mut pda = invoice_pda(&program_id, &invoice);
+        let mut wallet = TestAccount::new(destination, 0, vec![], system_program::id()).writable();
+        let mut system_program = TestAccount::system_program();
+        let mut clock = TestAccount::clock_sysvar(42, 1_700_000_100);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+
+        let data = borsh::to_vec(&InstructionData::ReleaseEscrow).unwrap();
+        let accounts = [authority.info(), pda.info(), vault.info(), wallet.info(), system_program.info(), clock.info(), config.info(), treasury.info()];
+        process_instruction(&program_id, &accounts, &data).unwrap();
+
+        assert_eq!(accounts[2].lamports(), 1_000);
+        assert_eq!(accounts[3].lamports(), 500);
+        let invoice = load_invoice(&program_id, &accounts[1]).unwrap();
+        assert!(invoice.escrow.unwrap().settled);
+
+        let err = process_instruction(&program_id, &accounts, &data).unwrap_err();
+        assert_eq!(err, InvoiceError::EscrowSettled.into());
+    }
+
+    #[test]
+    fn release_escrow_by_issuer_waits_for_timeout() {
+        let program_id = Pubkey::new_unique();
+        let issuer = Pubkey::new_unique();
+        let destination = Pubkey::new_unique();
+        let (invoice, mut vault) = escrow_invoice(&program_id, &issuer, &destination, &Pubkey::new_unique(), &Pubkey::new_unique());
+        let mut authority = TestAccount::new(issuer, 0, vec![], system_program::id()).signer();
+        let mut pda = invoice_pda(&program_id, &invoice);
+        let mut wallet = TestAccount::new(destination, 0, vec![], system_program::id()).writable();
+        let mut system_program = TestAccount::system_program();
+        let mut early = TestAccount::clock_sysvar(42, 1_700_003_600);
+        let mut late = TestAccount::clock_sysvar(43, 1_700_003_601);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+
+        let data = borsh::to_vec(&InstructionData::ReleaseEscrow).unwrap();
+        let accounts = [authority.info(), pda.info(), vault.info(), wallet.info(), system_program.info(), early.info(), config.info(), treasury.info()];
+        let err = process_instruction(&program_id, &accounts, &data).unwrap_err();
+        assert_eq!(err, InvoiceError::EscrowLocked.into());
+
+        let accounts = [accounts[0].clone(), accounts[1].clone(), accounts[2].clone(), accounts[3].clone(), accounts[4].clone(), late.info(), accounts[6].clone(), accounts[7].clone()];
+        process_instruction(&program_id, &accounts, &data).unwrap();
+        assert_eq!(accounts[3].lamports(), 500);
+    }
+
+    #[test]
+    fn dispute_refund_returns_funds_to_payer() {
+        let program_id = Pubkey::new_unique();
+        let arbiter = Pubkey::new_unique();
+        let payer = Pubkey::new_unique();
+        let (invoice, mut vault) = escrow_invoice(&program_id, &Pubkey::new_unique(), &Pubkey::new_unique(), &payer, &arbiter);
+        let mut authority = TestAccount::new(arbiter, 0, vec![], system_program::id()).signer();
+        let mut stranger = TestAccount::wallet(0).signer();
+        let mut pda = invoice_pda(&program_id, &invoice);
+        let mut payer = TestAccount::new(payer, 0, vec![], system_program::id()).writable();
+        let mut system_program = TestAccount::system_program();
+        let data = borsh::to_vec(&InstructionData::DisputeRefund).unwrap();
+
+        let accounts = [stranger.info(), pda.info(), vault.info(), payer.info(), system_program.info()];
+        let err = process_instruction(&program_id, &accounts, &data).unwrap_err();
+        assert_eq!(err, InvoiceError::InvalidEscrowAuthority.into());
+
+        let accounts = [authority.info(), accounts[1].clone(), accounts[2].clone(), accounts[3].clone(), accounts[4].clone()];
+        process_instruction(&program_id, &accounts, &data).unwrap();
+
+        assert_eq!(accounts[3].lamports(), 500);
+        let invoice = load_invoice(&program_id, &accounts[1]).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::Refunded);
+        assert_eq!(invoice.amount_refunded, 500);
+        assert!(invoice.escrow.unwrap().settled);
+    }
+
+    #[test]
+    fn split_portions_add_up_across_partial_payments() {
+        let split = Split { recipient: Pubkey::new_unique(), share: Share::Bps(333) };
+        assert_eq!(split.total(1_001), 33);
+
+        let portions: Vec<u64> = [(0, 500), (500, 999), (999, 1_001)]
+            .iter()
+            .map(|&(before, after)| split.portion(1_001, 1_001, before, after))
+            .collect();
+        assert_eq!(portions, [16, 16, 1]);
+        assert_eq!(portions.iter().sum::<u64>(), split.total(1_001));
+    }
+
+    #[test]
+    fn check_splits_rejects_shares_above_amount() {
+        let program_id = Pubkey::new_unique();
+        let mut invoice = open_invoice(&program_id, &Pubkey::new_unique(), 1, 1_000, &Pubkey::new_unique());
+        invoice.splits = vec![
+            Split { recipient: Pubkey::new_unique(), share: Share::Bps(9_000) },
+            Split { recipient: Pubkey::new_unique(), share: Share::Fixed(100) },
+        ];
+        assert_eq!(check_splits(&invoice), Ok(()));
+
+        invoice.splits[1].share = Share::Fixed(101);
+        assert_eq!(check_splits(&invoice), Err(InvoiceError::InvalidSplits.into()));
+
+        invoice.splits[1].share = Share::Bps(0);
+        assert_eq!(check_splits(&invoice), Err(InvoiceError::InvalidSplits.into()));
+
+        invoice.splits[1].share = Share::Fixed(100);
+        invoice.fee = 10;
+        assert_eq!(check_splits(&invoice), Err(InvoiceError::InvalidSplits.into()));
+    }
+
+    #[test]
+    fn pay_invoice_splits_payment_between_recipients() {
+        let program_id = Pubkey::new_unique();
+        let mut sender = TestAccount::wallet(1_000_000).signer();
+        let mut destination = TestAccount::wallet(0).writable();
+        let mut platform = TestAccount::wallet(0).writable();
+        let mut affiliate = TestAccount::wallet(0).writable();
+        let mut invoice = open_invoice(&program_id, &Pubkey::new_unique(), 3, 1_000, &destination.key);
+        invoice.splits = vec![
+            Split { recipient: platform.key, share: Share::Bps(250) },
+            Split { recipient: affiliate.key, share: Share::Fixed(40) },
+        ];
+        let mut pda = invoice_pda(&program_id, &invoice);
+        let mut system_program = TestAccount::system_program();
+        let mut clock = TestAccount::clock_sysvar(42, 1_700_000_000);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+
+        let accounts = [sender.info(), pda.info(), destination.info(), system_program.info(), clock.info(), config.info(), treasury.info(), affiliate.info(), platform.info()];
+        let err = process_instruction(&program_id, &accounts, &pay_invoice_data()).unwrap_err();
+        assert_eq!(err, InvoiceError::InvalidSplitRecipient.into());
+
+        let accounts = [accounts[0].clone(), accounts[1].clone(), accounts[2].clone(), accounts[3].clone(), accounts[4].clone(), accounts[5].clone(), accounts[6].clone(), accounts[8].clone(), accounts[7].clone()];
+        process_instruction(&program_id, &accounts, &pay_invoice_data()).unwrap();
+
+        let invoice = load_invoice(&program_id, &accounts[1]).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::Paid);
+    }
+
+    #[test]
+    fn pay_invoice_keeps_fee_the_invoice_was_created_with() {
+        let program_id = Pubkey::new_unique();
+        let mut sender = TestAccount::wallet(1_000_000).signer();
+        let mut destination = TestAccount::wallet(0).writable();
+        let mut platform = TestAccount::wallet(0).writable();
+        let mut invoice = open_invoice(&program_id, &Pubkey::new_unique(), 36, 1_000, &destination.key);
+        invoice.fee = 5;
+        invoice.splits = vec![Split { recipient: platform.key, share: Share::Fixed(995) }];
+        let mut pda = invoice_pda(&program_id, &invoice);
+        let mut system_program = TestAccount::system_program();
+        let mut clock = TestAccount::clock_sysvar(42, 1_700_000_000);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+
+        let config = config.info();
+        let mut state = load_test_config(&program_id, &config);
+        state.fee_bps = 100;
+        save_config(&config, state).unwrap();
+
+        // Raising the fee after creation would leave no room for the fixed split
+        let accounts = [sender.info(), pda.info(), destination.info(), system_program.info(), clock.info(), config, treasury.info(), platform.info()];
+        process_instruction(&program_id, &accounts, &borsh::to_vec(&InstructionData::PayInvoicePartial { amount: 400 }).unwrap()).unwrap();
+        process_instruction(&program_id, &accounts, &pay_invoice_data()).unwrap();
+
+        let invoice = load_invoice(&program_id, &accounts[1]).unwrap();
+        assert_eq!(invoice.status, InvoiceStatus::Paid);
+        assert_eq!((invoice.fee, invoice.amount_received), (5, 0));
+    }
+
+    #[test]
+    fn protocol_fee_has_a_floor_and_never_exceeds_amount() {
+        let program_id = Pubkey::new_unique();
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut state = load_test_config(&program_id, &config.info());
+        state.fee_bps = 150;
+        state.fee_min = 20;
+
+        assert_eq!(state.fee(10_000), 150);
+        assert_eq!(state.fee(1_000), 20);
+        assert_eq!(state.fee(5), 5);
+        assert_eq!(pro_rata(state.fee(10_000), 10_000, 0, 3_333) + pro_rata(state.fee(10_000), 10_000, 3_333, 10_000), 150);
+    }
+
+    #[test]
+    fn set_fee_requires_admin_and_valid_bps() {
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::wallet(0).signer();
+        let mut stranger = TestAccount::wallet(0).signer();
+        let mut config = config_pda(&program_id, &admin.key, None);
+        let set_fee = |fee_bps| borsh::to_vec(&InstructionData::SetFee { fee_bps, fee_min: 1_000 }).unwrap();
+
+        let config = config.info();
+        assert_eq!(
+            process_instruction(&program_id, &[stranger.info(), config.clone()], &set_fee(100)),
+            Err(InvoiceError::InvalidAdmin.into()),
+        );
+
+        let admin = admin.info();
+        assert_eq!(
+            process_instruction(&program_id, &[admin.clone(), config.clone()], &set_fee(10_001)),
+            Err(InvoiceError::InvalidFee.into()),
+        );
+
+        process_instruction(&program_id, &[admin, config.clone()], &set_fee(100)).unwrap();
+        let state = load_test_config(&program_id, &config);
+        assert_eq!((state.fee_bps, state.fee_min), (100, 1_000));
+    }
+
+    #[test]
+    fn release_escrow_routes_fee_to_treasury() {
+        let program_id = Pubkey::new_unique();
+        let payer = Pubkey::new_unique();
+        let destination = Pubkey::new_unique();
+        let (mut invoice, mut vault) = escrow_invoice(&program_id, &Pubkey::new_unique(), &destination, &payer, &Pubkey::new_unique());
+        invoice.fee = 10;
+        let mut authority = TestAccount::new(payer, 0, vec![], system_program::id()).signer();
+        let mut pda = invoice_pda(&program_id, &invoice);
+        let mut wallet = TestAccount::new(destination, 0, vec![], system_program::id()).writable();
+        let mut system_program = TestAccount::system_program();
+        let mut clock = TestAccount::clock_sysvar(42, 1_700_000_100);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+
+        // A fee raised after the invoice was created doesn't apply to it
+        let config = config.info();
+        let mut state = load_test_config(&program_id, &config);
+        state.fee_bps = 500;
+        save_config(&config, state).unwrap();
+
+        let data = borsh::to_vec(&InstructionData::ReleaseEscrow).unwrap();
+        let accounts = [authority.info(), pda.info(), vault.info(), wallet.info(), system_program.info(), clock.info(), config, treasury.info()];
+        process_instruction(&program_id, &accounts, &data).unwrap();
+
+        assert_eq!(accounts[3].lamports(), 490);
+        assert_eq!(accounts[7].lamports(), 890_890);
+    }
+
+    #[test]
+    fn withdraw_treasury_keeps_it_rent_exempt() {
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::wallet(0).signer();
+        let mut config = config_pda(&program_id, &admin.key, None);
+        let mut treasury = treasury_pda(&program_id);
+        treasury.lamports += 1_000;
+        let mut recipient = TestAccount::wallet(0).writable();
+        let mut system_program = TestAccount::system_program();
+        let mut rent = TestAccount::rent_sysvar();
+        let withdraw = |amount| borsh::to_vec(&InstructionData::WithdrawTreasury { mint: None, amount }).unwrap();
+
+        let accounts = [admin.info(), config.info(), treasury.info(), recipient.info(), system_program.info(), rent.info()];
+        assert_eq!(process_instruction(&program_id, &accounts, &withdraw(1_001)), Err(ProgramError::InsufficientFunds));
+
+        process_instruction(&program_id, &accounts, &withdraw(1_000)).unwrap();
+        assert_eq!(accounts[2].lamports(), 890_880);
+        assert_eq!(accounts[3].lamports(), 1_000);
+    }
+
+    #[test]
+    fn charge_subscription_waits_for_due_date() {
+        let program_id = Pubkey::new_unique();
+        let merchant = Pubkey::new_unique();
+        let mut keeper = TestAccount::wallet(1_000_000).signer();
+        let mut subscription = subscription_pda(&program_id, &merchant, &Pubkey::new_unique(), 1_700_000_000);
+        let mut pda = TestAccount::wallet(0).writable();
+        let mut system_program = TestAccount::system_program();
+        let mut rent = TestAccount::rent_sysvar();
+        let mut clock = TestAccount::clock_sysvar(42, 1_699_999_999);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+
+        let accounts = [keeper.info(), subscription.info(), pda.info(), system_program.info(), rent.info(), clock.info(), config.info(), treasury.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &borsh::to_vec(&InstructionData::ChargeSubscription).unwrap()),
+            Err(InvoiceError::SubscriptionNotDue.into()),
+        );
+    }
+
+    #[test]
+    fn charge_subscription_rejects_overflowing_schedule() {
+        let program_id = Pubkey::new_unique();
+        let merchant = Pubkey::new_unique();
+        let mut keeper = TestAccount::wallet(1_000_000).signer();
+        let mut subscription = subscription_pda(&program_id, &merchant, &Pubkey::new_unique(), i64::MAX - 10);
+        let mut pda = TestAccount::wallet(0).writable();
+        let mut system_program = TestAccount::system_program();
+        let mut rent = TestAccount::rent_sysvar();
+        let mut clock = TestAccount::clock_sysvar(42, i64::MAX - 5);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        let mut treasury = treasury_pda(&program_id);
+
+        let accounts = [keeper.info(), subscription.info(), pda.info(), system_program.info(), rent.info(), clock.info(), config.info(), treasury.info()];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &borsh::to_vec(&InstructionData::ChargeSubscription).unwrap()),
+            Err(InvoiceError::InvalidDeadline.into()),
+        );
+    }
+
+    #[test]
+    fn subscription_cycles_get_distinct_invoice_ids() {
+        let program_id = Pubkey::new_unique();
+        let mut subscription = subscription_pda(&program_id, &Pubkey::new_unique(), &Pubkey::new_unique(), 0);
+        let subscription = subscription.info();
+        let mut state = load_subscription(&program_id, &subscription).unwrap();
+
+        let first = state.cycle_invoice_id(subscription.key);
+        state.cycle += 1;
+        assert_ne!(first, state.cycle_invoice_id(subscription.key));
+        assert_ne!(first, state.cycle_invoice_id(&Pubkey::new_unique()));
+    }
+
+    #[test]
+    fn cancel_subscription_returns_rent_to_subscriber() {
+        let program_id = Pubkey::new_unique();
+        let mut merchant = TestAccount::wallet(0).signer();
+        let mut stranger = TestAccount::wallet(0).signer();
+        let mut subscriber = TestAccount::wallet(0).writable();
+        let mut subscription = subscription_pda(&program_id, &merchant.key, &subscriber.key, 0);
+        let cancel = borsh::to_vec(&InstructionData::CancelSubscription).unwrap();
+
+        let subscription = subscription.info();
+        let subscriber = subscriber.info();
+        assert_eq!(
+            process_instruction(&program_id, &[stranger.info(), subscription.clone(), subscriber.clone()], &cancel),
+            Err(InvoiceError::InvalidSubscriptionAuthority.into()),
+        );
+
+        process_instruction(&program_id, &[merchant.info(), subscription.clone(), subscriber.clone()], &cancel).unwrap();
+        assert_eq!(subscriber.lamports(), 1_000);
+        assert_eq!(subscription.lamports(), 0);
+    }
+
+    #[test]
+    fn accounts_of_another_type_are_rejected() {
+        let program_id = Pubkey::new_unique();
+        let merchant = Pubkey::new_unique();
+        let mut registry = merchant_pda(&program_id, &merchant);
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+        config.key = registry.key;
+
+        assert_eq!(load_merchant(&program_id, &config.info(), &merchant).unwrap_err(), InvoiceError::InvalidAccountType.into());
+
+        registry.data[8] = Merchant::VERSION + 1;
+        assert_eq!(load_merchant(&program_id, &registry.info(), &merchant).unwrap_err(), InvoiceError::UnsupportedAccountVersion.into());
+    }
+
+    /// Invoice account of the first program version: id, amount, paid flag and destination.
+    fn legacy_invoice_pda(program_id: &Pubkey, id: u128, paid: bool, destination: &Pubkey) -> TestAccount {
+        let (key, _) = Pubkey::find_program_address(&[&id.to_be_bytes()], program_id);
+        let data = [id.to_le_bytes().as_slice(), &500u64.to_le_bytes(), &[u8::from(paid)], destination.as_ref()].concat();
+        TestAccount::new(key, 1_000_000, data, *program_id).writable()
+    }
+
+    #[test]
+    fn legacy_invoice_must_be_migrated() {
+        let program_id = Pubkey::new_unique();
+        let invoice = open_invoice(&program_id, &Pubkey::new_unique(), 30, 500, &Pubkey::new_unique());
+        let mut payer = TestAccount::wallet(1_000_000).signer();
+        let mut legacy = legacy_invoice_pda(&program_id, 30, false, &Pubkey::new_unique());
+        let mut current = invoice_pda(&program_id, &invoice);
+        let mut system_program = TestAccount::system_program();
+        let mut rent = TestAccount::rent_sysvar();
+
+        assert_eq!(load_invoice(&program_id, &legacy.info()).unwrap_err(), InvoiceError::InvalidAccountType.into());
+        assert_eq!(
+            process_instruction(
+                &program_id,
+                &[payer.info(), current.info(), system_program.info(), rent.info()],
+                &borsh::to_vec(&InstructionData::MigrateInvoice).unwrap(),
+            ),
+            Err(InvoiceError::AccountUpToDate.into()),
+        );
+    }
+
+    #[test]
+    fn legacy_invoice_upgrade_keeps_payment_state() {
+        let program_id = Pubkey::new_unique();
+        let destination = Pubkey::new_unique();
+
+        for (paid, status, amount_paid) in [(false, InvoiceStatus::Open, 0), (true, InvoiceStatus::Paid, 500)] {
+            let legacy = legacy_invoice_pda(&program_id, 30, paid, &destination);
+            let invoice = LegacyInvoiceV1::try_from_slice(&legacy.data).unwrap().upgrade(254);
+
+            assert_eq!((invoice.id, invoice.amount, invoice.destination), (30, 500, destination.to_bytes()));
+            assert_eq!((invoice.status, invoice.amount_paid, invoice.amount_received), (status, amount_paid, amount_paid));
+            assert_eq!((invoice.issuer, invoice.rent_receiver), (LEGACY_ADMIN, LEGACY_ADMIN));
+            assert_eq!((invoice.legacy_address, invoice.bump), (true, 254));
+        }
+    }
+
+    #[test]
+    fn migrate_invoice_rejects_legacy_invoice_at_other_address() {
+        let program_id = Pubkey::new_unique();
+        let mut payer = TestAccount::wallet(1_000_000).signer();
+        let mut legacy = legacy_invoice_pda(&program_id, 30, false, &Pubkey::new_unique());
+        legacy.key = Pubkey::find_program_address(&[&31u128.to_be_bytes()], &program_id).0;
+        let mut system_program = TestAccount::system_program();
+        let mut rent = TestAccount::rent_sysvar();
+
+        assert_eq!(
+            process_instruction(
+                &program_id,
+                &[payer.info(), legacy.info(), system_program.info(), rent.info()],
+                &borsh::to_vec(&InstructionData::MigrateInvoice).unwrap(),
+            ),
+            Err(InvoiceError::InvalidInvoiceAddress.into()),
+        );
+    }
+
+    #[test]
+    fn update_invoice_metadata_rewrites_tail() {
+        let program_id = Pubkey::new_unique();
+        let mut issuer = TestAccount::wallet(1_000_000).signer();
+        let invoice = open_invoice(&program_id, &issuer.key, 31, 500, &Pubkey::new_unique());
+        let metadata = |memo: &str| InvoiceMetadata {
+            memo: Some(memo.to_string()),
+            line_items: vec![LineItem { sku_hash: [7; 32], quantity: 5, unit_price: 100, tax_bps: 0 }],
+        };
+        let mut pda = invoice_pda(&program_id, &invoice);
+        pda.data.extend(borsh::to_vec(&metadata("march")).unwrap());
+        pda.lamports = 5_000_000;
+        let mut system_program = TestAccount::system_program();
+        let mut rent = TestAccount::rent_sysvar();
+
+        let issuer = issuer.info();
+        let accounts = [issuer.clone(), pda.info(), issuer, system_program.info(), rent.info()];
+        assert_eq!(InvoiceMetadata::unpack(&accounts[1].data.borrow(), &invoice).unwrap(), metadata("march"));
+
+        let update = borsh::to_vec(&InstructionData::UpdateInvoiceMetadata(metadata("april"))).unwrap();
+        process_instruction(&program_id, &accounts, &update).unwrap();
+
+        assert_eq!(InvoiceMetadata::unpack(&accounts[1].data.borrow(), &invoice).unwrap(), metadata("april"));
+        let minimum_balance = Rent::default().minimum_balance(accounts[1].data_len());
+        assert_eq!(accounts[1].lamports(), minimum_balance);
+        assert_eq!(accounts[0].lamports(), 1_000_000 + 5_000_000 - minimum_balance);
+    }
+
+    #[test]
+    fn update_invoice_metadata_rejects_other_rent_receiver() {
+        let program_id = Pubkey::new_unique();
+        let mut issuer = TestAccount::wallet(1_000_000).signer();
+        let mut invoice = open_invoice(&program_id, &issuer.key, 33, 500, &Pubkey::new_unique());
+        invoice.rent_receiver = Pubkey::new_unique();
+        let mut pda = invoice_pda(&program_id, &invoice);
+        let mut system_program = TestAccount::system_program();
+        let mut rent = TestAccount::rent_sysvar();
+        let update = borsh::to_vec(&InstructionData::UpdateInvoiceMetadata(InvoiceMetadata::default())).unwrap();
+
+        let issuer = issuer.info();
+        let accounts = [issuer.clone(), pda.info(), issuer, system_program.info(), rent.info()];
+        assert_eq!(process_instruction(&program_id, &accounts, &update), Err(InvoiceError::InvalidRentReceiver.into()));
+    }
+
+    #[test]
+    fn update_invoice_metadata_rejects_oversized_memo() {
+        let program_id = Pubkey::new_unique();
+        let mut issuer = TestAccount::wallet(0).signer();
+        let mut stranger = TestAccount::wallet(0).signer();
+        let mut pda = invoice_pda(&program_id, &open_invoice(&program_id, &issuer.key, 32, 500, &Pubkey::new_unique()));
+        let mut system_program = TestAccount::system_program();
+        let mut rent = TestAccount::rent_sysvar();
+        let update = |memo: String| borsh::to_vec(&InstructionData::UpdateInvoiceMetadata(InvoiceMetadata { memo: Some(memo), line_items: vec![] })).unwrap();
+
+        let issuer = issuer.info();
+        let pda = pda.info();
+        let system_program = system_program.info();
+        let rent = rent.info();
+        assert_eq!(
+            process_instruction(&program_id, &[stranger.info(), pda.clone(), issuer.clone(), system_program.clone(), rent.clone()], &update("memo".to_string())),
+            Err(InvoiceError::InvalidIssuer.into()),
+        );
+        assert_eq!(
+            process_instruction(&program_id, &[issuer.clone(), pda, issuer, system_program, rent], &update("x".repeat(MAX_MEMO_LEN + 1))),
+            Err(InvoiceError::InvalidMetadata.into()),
+        );
+    }
+
+    #[test]
+    fn line_items_must_add_up_to_amount() {
+        let item = |quantity, unit_price, tax_bps| LineItem { sku_hash: [1; 32], quantity, unit_price, tax_bps };
+        let metadata = |line_items| InvoiceMetadata { memo: None, line_items };
+
+        // 3 * 199 = 597 plus 8.25% tax rounded down to 49, and 2 * 50 tax free
+        let items = vec![item(3, 199, 825), item(2, 50, 0)];
+        assert_eq!(check_metadata(&metadata(items.clone()), 746), Ok(()));
+        assert_eq!(check_metadata(&metadata(items), 747), Err(InvoiceError::AmountMismatch.into()));
+
+        assert_eq!(
+            check_metadata(&metadata(vec![item(u64::MAX, 2, 0)]), 0),
+            Err(InvoiceError::LineItemsOverflow.into()),
+        );
+        assert_eq!(
+            check_metadata(&metadata(vec![item(1, u64::MAX, 0), item(1, 1, 0)]), 0),
+            Err(InvoiceError::LineItemsOverflow.into()),
+        );
+        assert_eq!(
+            check_metadata(&metadata(vec![item(1, u64::MAX, 1)]), 0),
+            Err(InvoiceError::LineItemsOverflow.into()),
+        );
+    }
+
+    #[test]
+    fn create_invoice_rejects_line_items_not_matching_amount() {
+        let program_id = Pubkey::new_unique();
+        let mut merchant = TestAccount::wallet(1_000_000).signer();
+        let mut pda = TestAccount::wallet(0).writable();
+        pda.key = Pubkey::find_program_address(&[merchant.key.as_ref(), &33u128.to_be_bytes()], &program_id).0;
+        let mut system_program = TestAccount::system_program();
+        let mut rent = TestAccount::rent_sysvar();
+        let mut registry = merchant_pda(&program_id, &merchant.key);
+        let args = CreateInvoiceArgs {
+            metadata: InvoiceMetadata {
+                memo: None,
+                line_items: vec![LineItem { sku_hash: [2; 32], quantity: 4, unit_price: 100, tax_bps: 0 }],
+            },
+            ..create_invoice_args(33, 500, &Pubkey::new_unique())
+        };
+        let mut config = config_pda(&program_id, &Pubkey::new_unique(), None);
+
+        let accounts = [merchant.info(), pda.info(), system_program.info(), rent.info(), registry.info(), config.info()];
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &borsh::to_vec(&InstructionData::CreateInvoice(Box::new(args))).unwrap()),
+            Err(InvoiceError::AmountMismatch.into()),
+        );
+    }
+}
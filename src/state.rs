@@ -0,0 +1,424 @@
+//! Accounts owned by the program.
+//!
+//! Every account starts with a header made of an 8-byte discriminator and a layout version,
+//! see [`AccountState`]. Use [`decode_account`] to decode an account of any type.
+
+use std::io::Write;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{entrypoint::ProgramResult, hash::hashv, program_error::ProgramError, pubkey::Pubkey};
+
+use crate::error::InvoiceError;
+
+pub const CONFIG_SEED: &[u8] = b"config";
+pub const MERCHANT_SEED: &[u8] = b"merchant";
+pub const VAULT_SEED: &[u8] = b"vault";
+pub const TREASURY_SEED: &[u8] = b"treasury";
+pub const SUBSCRIPTION_SEED: &[u8] = b"subscription";
+
+/// Admin that issued every invoice of the first program version, before admins were configured
+/// on chain.
+pub const LEGACY_ADMIN: Pubkey = Pubkey::from_str_const("HWd8ZyEzy7exV7UGLBb6Hf1it54WNPXtK5sMivepDmP");
+
+/// Every account of the program starts with an 8-byte discriminator followed by its layout version.
+pub const ACCOUNT_HEADER_LEN: usize = 9;
+
+pub const MAX_SPLITS: usize = 5;
+pub const MAX_MEMO_LEN: usize = 256;
+pub const MAX_LINE_ITEMS: usize = 16;
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+/// State of a program owned account, stored behind a header identifying its type and layout.
+pub trait AccountState: BorshSerialize + BorshDeserialize {
+    const DISCRIMINATOR: [u8; 8];
+    const VERSION: u8;
+
+    /// Account size needed to store the state with its header.
+    fn space(&self) -> Result<usize, ProgramError> {
+        Ok(ACCOUNT_HEADER_LEN + borsh::object_length(self)?)
+    }
+
+    /// Decodes the state after checking the account header. Trailing bytes are ignored.
+    fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        let Some((header, mut state)) = data.split_at_checked(ACCOUNT_HEADER_LEN) else {
+            return Err(InvoiceError::InvalidAccountType.into());
+        };
+
+        if header[..8] != Self::DISCRIMINATOR {
+            return Err(InvoiceError::InvalidAccountType.into());
+        }
+
+        if header[8] != Self::VERSION {
+            return Err(InvoiceError::UnsupportedAccountVersion.into());
+        }
+
+        Ok(Self::deserialize(&mut state)?)
+    }
+
+    /// Writes the header and the state at the start of `data`.
+    fn pack(&self, mut data: &mut [u8]) -> ProgramResult {
+        data.write_all(&Self::DISCRIMINATOR)?;
+        data.write_all(&[Self::VERSION])?;
+        self.serialize(&mut data)?;
+
+        Ok(())
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvoiceStatus {
+    Open,
+    Paid,
+    Cancelled,
+    PartiallyPaid,
+    Refunded,
+    PartiallyRefunded,
+}
+
+/// Invoice stored at the PDA derived from its issuer and id.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Invoice {
+    pub id: u128,
+    pub issuer: Pubkey,
+    pub amount: u64,
+    /// Protocol fee owed on the whole amount, set by the config when the invoice is created
+    pub fee: u64,
+    pub amount_paid: u64,
+    /// Part of the payments the destination received, net of the protocol fee and splits
+    pub amount_received: u64,
+    pub amount_refunded: u64,
+    pub status: InvoiceStatus,
+    pub destination: [u8; 32],
+    pub rent_receiver: Pubkey,
+    pub mint: Option<Pubkey>,
+    /// Unix timestamp after which a payment is recorded as late, 0 if there's no due date
+    pub due_at: i64,
+    /// Unix timestamp after which the invoice can't be paid anymore, 0 if it never expires
+    pub expires_at: i64,
+    pub late: bool,
+    /// Account the payments came from, the default pubkey until the first payment
+    pub payer: Pubkey,
+    /// Unix timestamp of the latest payment, 0 until the first payment
+    pub paid_at: i64,
+    /// Slot of the latest payment, 0 until the first payment
+    pub paid_slot: u64,
+    pub escrow: Option<Escrow>,
+    /// Shares of the invoice amount net of the protocol fee routed to other recipients, the
+    /// destination receives the rest
+    pub splits: Vec<Split>,
+    /// Set on invoices of the first program version, stored at the PDA derived from their id alone
+    pub legacy_address: bool,
+    pub bump: u8,
+}
+
+/// Escrow terms of an invoice whose payments are held in a vault PDA until released.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Escrow {
+    /// Account allowed to release the funds or return them to the payer
+    pub arbiter: Option<Pubkey>,
+    /// Seconds after the final payment after which the issuer can claim the funds itself
+    pub timeout: i64,
+    /// Set once the funds left the vault, either released or returned to the payer
+    pub settled: bool,
+    pub vault_bump: u8,
+}
+
+/// Share of an invoice paid out to a recipient other than the destination.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Split {
+    pub recipient: Pubkey,
+    pub share: Share,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Share {
+    /// Basis points of the invoice amount net of the protocol fee, rounded down
+    Bps(u16),
+    /// Fixed amount taken out of the invoice amount net of the protocol fee
+    Fixed(u64),
+}
+
+impl Split {
+    /// Part of `net_amount`, the invoice amount net of the protocol fee, owed to the recipient
+    /// once the invoice is fully paid.
+    pub fn total(&self, net_amount: u64) -> u64 {
+        match self.share {
+            Share::Bps(bps) => (u128::from(net_amount) * u128::from(bps) / u128::from(BPS_DENOMINATOR)) as u64,
+            Share::Fixed(amount) => amount,
+        }
+    }
+
+    /// Part of a payment raising the paid amount from `paid_before` to `paid_after` owed to the
+    /// recipient, out of an invoice of `invoice_amount` leaving `net_amount` after the protocol fee.
+    ///
+    /// The share accrues pro rata with the paid amount and is rounded down on the cumulative
+    /// amount, so partial payments add up to exactly the full share and the rounding dust of each
+    /// payment goes to the destination.
+    pub fn portion(&self, invoice_amount: u64, net_amount: u64, paid_before: u64, paid_after: u64) -> u64 {
+        pro_rata(self.total(net_amount), invoice_amount, paid_before, paid_after)
+    }
+}
+
+/// Part of `total` accrued by a payment raising the paid amount of an invoice from `paid_before`
+/// to `paid_after`, rounded down on the cumulative amount.
+pub(crate) fn pro_rata(total: u64, invoice_amount: u64, paid_before: u64, paid_after: u64) -> u64 {
+    if invoice_amount == 0 {
+        return 0;
+    }
+
+    let accrued = |paid: u64| (u128::from(total) * u128::from(paid) / u128::from(invoice_amount)) as u64;
+    accrued(paid_after) - accrued(paid_before)
+}
+
+/// Optional description of an invoice, stored after the invoice state and resized on update.
+/// Invoices without metadata don't store it at all.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct InvoiceMetadata {
+    pub memo: Option<String>,
+    pub line_items: Vec<LineItem>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LineItem {
+    /// Hash of the merchant's stock keeping unit identifier
+    pub sku_hash: [u8; 32],
+    pub quantity: u64,
+    pub unit_price: u64,
+    /// Tax in basis points of the item subtotal, rounded down
+    pub tax_bps: u16,
+}
+
+impl LineItem {
+    /// Subtotal of the item with its tax, `None` on overflow.
+    pub fn total(&self) -> Option<u64> {
+        let subtotal = self.quantity.checked_mul(self.unit_price)?;
+        let tax = u64::try_from(u128::from(subtotal) * u128::from(self.tax_bps) / u128::from(BPS_DENOMINATOR)).ok()?;
+        subtotal.checked_add(tax)
+    }
+}
+
+impl InvoiceMetadata {
+    /// Bytes taken by the metadata after the invoice state.
+    pub fn space(&self) -> Result<usize, ProgramError> {
+        if *self == Self::default() {
+            return Ok(0);
+        }
+
+        Ok(borsh::object_length(self)?)
+    }
+
+    /// Decodes the metadata stored after `invoice` in the invoice account `data`.
+    pub fn unpack(data: &[u8], invoice: &Invoice) -> Result<Self, ProgramError> {
+        match data.get(invoice.space()?..) {
+            None | Some([]) => Ok(Self::default()),
+            Some(metadata) => Ok(Self::try_from_slice(metadata)?),
+        }
+    }
+}
+
+impl AccountState for Invoice {
+    const DISCRIMINATOR: [u8; 8] = *b"invoice\0";
+    /// Version 1 invoices are the headerless ones of the first program version, upgraded by
+    /// `MigrateInvoice`
+    const VERSION: u8 = 2;
+}
+
+#[cfg(test)]
+impl Invoice {
+    /// Open invoice with no payment, options or splits, the base of the unit test fixtures.
+    pub(crate) fn open(id: u128, issuer: Pubkey, amount: u64, destination: [u8; 32], bump: u8) -> Self {
+        Invoice {
+            id,
+            issuer,
+            amount,
+            fee: 0,
+            amount_paid: 0,
+            amount_received: 0,
+            amount_refunded: 0,
+            status: InvoiceStatus::Open,
+            destination,
+            rent_receiver: issuer,
+            mint: None,
+            due_at: 0,
+            expires_at: 0,
+            late: false,
+            payer: Pubkey::default(),
+            paid_at: 0,
+            paid_slot: 0,
+            escrow: None,
+            splits: vec![],
+            legacy_address: false,
+            bump,
+        }
+    }
+}
+
+impl Invoice {
+    /// Amount still to be paid before the invoice is settled.
+    pub fn outstanding(&self) -> u64 {
+        self.amount.saturating_sub(self.amount_paid)
+    }
+
+    /// Amount paid that hasn't been refunded yet.
+    pub fn refundable(&self) -> u64 {
+        self.amount_paid.saturating_sub(self.amount_refunded)
+    }
+
+    /// Amount the destination can still refund: what it received of the payments minus past
+    /// refunds. The protocol fee and split shares aren't refunded.
+    pub fn destination_refundable(&self) -> u64 {
+        self.amount_received.saturating_sub(self.amount_refunded)
+    }
+
+    pub fn is_overdue(&self, now: i64) -> bool {
+        self.due_at != 0 && now > self.due_at
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at != 0 && now > self.expires_at
+    }
+}
+
+/// Program wide settings stored at the config PDA.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub admin: Pubkey,
+    pub pending_admin: Option<Pubkey>,
+    /// Protocol fee in basis points of the invoice amount
+    pub fee_bps: u16,
+    /// Minimum protocol fee charged on an invoice, whatever its basis points share
+    pub fee_min: u64,
+    pub treasury_bump: u8,
+    pub bump: u8,
+}
+
+impl AccountState for Config {
+    const DISCRIMINATOR: [u8; 8] = *b"config\0\0";
+    const VERSION: u8 = 1;
+}
+
+impl Config {
+    /// Protocol fee owed on a fully paid invoice of `amount`, never more than the amount itself.
+    pub fn fee(&self, amount: u64) -> u64 {
+        let fee = (u128::from(amount) * u128::from(self.fee_bps) / u128::from(BPS_DENOMINATOR)) as u64;
+        fee.max(self.fee_min).min(amount)
+    }
+}
+
+/// Registry entry allowing a merchant to issue invoices.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Merchant {
+    pub merchant: Pubkey,
+    pub bump: u8,
+}
+
+impl AccountState for Merchant {
+    const DISCRIMINATOR: [u8; 8] = *b"merchant";
+    const VERSION: u8 = 1;
+}
+
+/// Recurring token payment pulled from the subscriber through an SPL token delegate
+/// approved to the subscription PDA.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Subscription {
+    pub id: u128,
+    pub merchant: Pubkey,
+    pub subscriber: Pubkey,
+    pub destination: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+    /// Length of a billing period in seconds
+    pub period: i64,
+    /// Unix timestamp from which the next cycle can be charged
+    pub next_due_at: i64,
+    /// Number of cycles charged so far
+    pub cycle: u64,
+    pub bump: u8,
+}
+
+impl AccountState for Subscription {
+    const DISCRIMINATOR: [u8; 8] = *b"subscrpt";
+    const VERSION: u8 = 1;
+}
+
+impl Subscription {
+    /// Id of the invoice recording the current cycle, unique per subscription and cycle.
+    pub fn cycle_invoice_id(&self, subscription: &Pubkey) -> u128 {
+        let hash = hashv(&[subscription.as_ref(), &self.cycle.to_be_bytes()]);
+        u128::from_be_bytes(hash.to_bytes()[..16].try_into().unwrap())
+    }
+}
+
+/// Account owned by the program, decoded with [`decode_account`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgramAccount {
+    Invoice(Invoice, InvoiceMetadata),
+    Config(Config),
+    Merchant(Merchant),
+    Subscription(Subscription),
+}
+
+/// Decodes the data of an account owned by the program, checking its discriminator and layout
+/// version and that invoice metadata spans the rest of the account.
+///
+/// Escrow vaults and the treasury hold no state and can't be decoded.
+pub fn decode_account(data: &[u8]) -> Result<ProgramAccount, ProgramError> {
+    let discriminator = data.get(..8).ok_or(InvoiceError::InvalidAccountType)?;
+
+    let account = if discriminator == Invoice::DISCRIMINATOR {
+        let invoice = Invoice::unpack(data)?;
+        let metadata = InvoiceMetadata::unpack(data, &invoice)?;
+        ProgramAccount::Invoice(invoice, metadata)
+    } else if discriminator == Config::DISCRIMINATOR {
+        ProgramAccount::Config(Config::unpack(data)?)
+    } else if discriminator == Merchant::DISCRIMINATOR {
+        ProgramAccount::Merchant(Merchant::unpack(data)?)
+    } else if discriminator == Subscription::DISCRIMINATOR {
+        ProgramAccount::Subscription(Subscription::unpack(data)?)
+    } else {
+        return Err(InvoiceError::InvalidAccountType.into());
+    };
+
+    Ok(account)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packed<T: AccountState>(state: &T) -> Vec<u8> {
+        let mut data = vec![0; state.space().unwrap()];
+        state.pack(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn decode_account_dispatches_on_discriminator() {
+        let merchant = Merchant { merchant: Pubkey::new_unique(), bump: 254 };
+        assert_eq!(decode_account(&packed(&merchant)), Ok(ProgramAccount::Merchant(merchant)));
+
+        let config = Config { admin: Pubkey::new_unique(), pending_admin: None, fee_bps: 25, fee_min: 0, treasury_bump: 253, bump: 255 };
+        let mut data = packed(&config);
+        // Room reserved for a pending admin is ignored
+        data.extend([0; 32]);
+        assert_eq!(decode_account(&data), Ok(ProgramAccount::Config(config)));
+
+        assert_eq!(decode_account(b"unknown\0"), Err(InvoiceError::InvalidAccountType.into()));
+        assert_eq!(decode_account(&[]), Err(InvoiceError::InvalidAccountType.into()));
+    }
+
+    #[test]
+    fn decode_account_reads_invoice_metadata() {
+        let invoice = Invoice::open(1, Pubkey::new_unique(), 300, [3; 32], 255);
+        let metadata = InvoiceMetadata { memo: Some("consulting".to_string()), line_items: vec![] };
+
+        let mut data = packed(&invoice);
+        assert_eq!(decode_account(&data), Ok(ProgramAccount::Invoice(invoice.clone(), InvoiceMetadata::default())));
+
+        data.extend(borsh::to_vec(&metadata).unwrap());
+        assert_eq!(decode_account(&data), Ok(ProgramAccount::Invoice(invoice, metadata)));
+
+        data.push(0);
+        assert!(decode_account(&data).is_err());
+    }
+}
@@ -0,0 +1,338 @@
+//! Compute units consumed by the program instructions, checked with `mollusk-svm` against the SBF
+//! build of the program.
+//!
+//! The tests only compile with the `test-sbf` feature, so `cargo test` skips them. Run them with
+//! `cargo test-sbf --test compute_units`, which builds `otus_program.so` and points `SBF_OUT_DIR`
+//! at it. The `compute-units` CI job runs the same command. A budget should only be raised along
+//! with the change that legitimately needs the extra units.
+
+#![cfg(feature = "test-sbf")]
+
+use mollusk_svm::{
+    program::{create_program_account_loader_v2, keyed_account_for_system_program, loader_keys::LOADER_V2},
+    result::Check,
+    Mollusk,
+};
+use otus_program::{
+    client::{create_invoice_ix, find_config_address, find_invoice_address, find_merchant_address, find_treasury_address, pay_invoice_ix},
+    instruction::CreateInvoiceArgs,
+    state::{AccountState, Config, Invoice, InvoiceMetadata, InvoiceStatus, Merchant, Share, Split},
+};
+use solana_program::{program_option::COption, program_pack::Pack};
+use solana_program_test::programs::spl_programs;
+use solana_sdk::{account::Account, native_token::LAMPORTS_PER_SOL, pubkey::Pubkey};
+use solana_sdk_ids::system_program;
+use spl_associated_token_account_client::address::get_associated_token_address_with_program_id;
+use spl_token_2022::state::{Account as TokenAccount, AccountState as TokenAccountState, Mint};
+
+// Upper bounds that haven't been measured against the SBF build yet. Each budget should be set
+// just above the units the `compute-units` CI job reports for it, with that figure noted here.
+const CREATE_INVOICE_BUDGET: u64 = 25_000;
+const PAY_INVOICE_BUDGET: u64 = 20_000;
+const PAY_SPLIT_INVOICE_BUDGET: u64 = 25_000;
+const PAY_TOKEN_INVOICE_BUDGET: u64 = 60_000;
+
+const SPL_TOKEN_ID: Pubkey = Pubkey::from_str_const("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA");
+
+const AMOUNT: u64 = 500_000_000;
+
+struct Fixture {
+    mollusk: Mollusk,
+    program_id: Pubkey,
+    merchant: Pubkey,
+    args: CreateInvoiceArgs,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        let program_id = Pubkey::new_unique();
+        let args = CreateInvoiceArgs {
+            id: 1,
+            amount: AMOUNT,
+            destination: Pubkey::new_unique().to_bytes(),
+            rent_receiver: None,
+            mint: None,
+            due_at: None,
+            expires_at: None,
+            escrow: None,
+            splits: vec![],
+            metadata: InvoiceMetadata::default(),
+        };
+
+        Self { mollusk: Mollusk::new(&program_id, "otus_program"), program_id, merchant: Pubkey::new_unique(), args }
+    }
+
+    fn wallet(&self, key: Pubkey, lamports: u64) -> (Pubkey, Account) {
+        (key, Account::new(lamports, 0, &system_program::id()))
+    }
+
+    fn program_account<T: AccountState>(&self, key: Pubkey, state: &T) -> (Pubkey, Account) {
+        let mut data = vec![0; state.space().unwrap()];
+        state.pack(&mut data).unwrap();
+        let lamports = self.mollusk.sysvars.rent.minimum_balance(data.len());
+        (key, Account { lamports, data, owner: self.program_id, executable: false, rent_epoch: 0 })
+    }
+
+    fn config(&self) -> (Pubkey, Account) {
+        let (key, bump) = find_config_address(&self.program_id);
+        let (_, treasury_bump) = find_treasury_address(&self.program_id);
+        let config = Config { admin: Pubkey::new_unique(), pending_admin: None, fee_bps: 100, fee_min: 0, treasury_bump, bump };
+        self.program_account(key, &config)
+    }
+
+    fn treasury(&self) -> (Pubkey, Account) {
+        let (key, _) = find_treasury_address(&self.program_id);
+        let lamports = self.mollusk.sysvars.rent.minimum_balance(0);
+        (key, Account::new(lamports, 0, &self.program_id))
+    }
+
+    fn merchant_registry(&self) -> (Pubkey, Account) {
+        let (key, bump) = find_merchant_address(&self.program_id, &self.merchant);
+        self.program_account(key, &Merchant { merchant: self.merchant, bump })
+    }
+
+    /// Invoice as stored by `create_invoice` with the fixture args.
+    fn invoice_state(&self) -> Invoice {
+        let (_, bump) = find_invoice_address(&self.program_id, &self.merchant, self.args.id);
+        Invoice {
+            id: self.args.id,
+            issuer: self.merchant,
+            amount: self.args.amount,
+            // 100 bps of the fixture config
+            fee: AMOUNT / 100,
+            amount_paid: 0,
+            amount_received: 0,
+            amount_refunded: 0,
+            status: InvoiceStatus::Open,
+            destination: self.args.destination,
+            rent_receiver: self.merchant,
+            mint: self.args.mint,
+            due_at: 0,
+            expires_at: 0,
+            late: false,
+            payer: Pubkey::default(),
+            paid_at: 0,
+            paid_slot: 0,
+            escrow: None,
+            splits: self.args.splits.clone(),
+            legacy_address: false,
+            bump,
+        }
+    }
+
+    fn invoice(&self) -> (Pubkey, Account) {
+        let (key, _) = find_invoice_address(&self.program_id, &self.merchant, self.args.id);
+        self.program_account(key, &self.invoice_state())
+    }
+
+    /// Loads the SPL Token program bundled with `solana-program-test` and returns its account.
+    fn add_token_program(&mut self) -> (Pubkey, Account) {
+        let (_, program) = spl_programs(&self.mollusk.sysvars.rent).into_iter().find(|(key, _)| *key == SPL_TOKEN_ID).unwrap();
+        let elf = Account::from(program).data;
+        self.mollusk.add_program_with_elf_and_loader(&SPL_TOKEN_ID, &elf, &LOADER_V2);
+        (SPL_TOKEN_ID, create_program_account_loader_v2(&elf))
+    }
+
+    fn mint(&self, key: Pubkey) -> (Pubkey, Account) {
+        let state = Mint { mint_authority: COption::None, supply: AMOUNT, decimals: 6, is_initialized: true, freeze_authority: COption::None };
+        let mut data = vec![0; Mint::LEN];
+        state.pack_into_slice(&mut data);
+        let lamports = self.mollusk.sysvars.rent.minimum_balance(data.len());
+        (key, Account { lamports, data, owner: SPL_TOKEN_ID, executable: false, rent_epoch: 0 })
+    }
+
+    /// Associated token account of `wallet` for `mint`, holding `amount`.
+    fn token_account(&self, wallet: &Pubkey, mint: &Pubkey, amount: u64) -> (Pubkey, Account) {
+        let state = TokenAccount {
+            mint: *mint,
+            owner: *wallet,
+            amount,
+            delegate: COption::None,
+            state: TokenAccountState::Initialized,
+            is_native: COption::None,
+            delegated_amount: 0,
+            close_authority: COption::None,
+        };
+        let mut data = vec![0; TokenAccount::LEN];
+        state.pack_into_slice(&mut data);
+        let lamports = self.mollusk.sysvars.rent.minimum_balance(data.len());
+        let key = get_associated_token_address_with_program_id(wallet, mint, &SPL_TOKEN_ID);
+        (key, Account { lamports, data, owner: SPL_TOKEN_ID, executable: false, rent_epoch: 0 })
+    }
+}
+
+#[test]
+fn create_invoice_compute_units() {
+    let fixture = Fixture::new();
+    let (invoice, _) = find_invoice_address(&fixture.program_id, &fixture.merchant, fixture.args.id);
+
+    let ix = create_invoice_ix(&fixture.program_id, &fixture.merchant, None, fixture.args.clone()).unwrap();
+    let accounts = [
+        fixture.wallet(fixture.merchant, LAMPORTS_PER_SOL),
+        fixture.wallet(invoice, 0),
+        keyed_account_for_system_program(),
+        fixture.mollusk.sysvars.keyed_account_for_rent_sysvar(),
+        fixture.merchant_registry(),
+        fixture.config(),
+    ];
+
+    let result = fixture.mollusk.process_and_validate_instruction(
+        &ix,
+        &accounts,
+        &[Check::success(), Check::account(&invoice).owner(&fixture.program_id).rent_exempt().build()],
+    );
+
+    assert!(
+        result.compute_units_consumed <= CREATE_INVOICE_BUDGET,
+        "create_invoice consumed {} CUs, over its budget of {CREATE_INVOICE_BUDGET}",
+        result.compute_units_consumed,
+    );
+}
+
+#[test]
+fn pay_invoice_compute_units() {
+    let fixture = Fixture::new();
+    let sender = Pubkey::new_unique();
+    let destination = Pubkey::new_from_array(fixture.args.destination);
+    let (treasury, treasury_account) = fixture.treasury();
+    let treasury_lamports = treasury_account.lamports;
+
+    let ix = pay_invoice_ix(&fixture.program_id, &sender, &fixture.invoice_state(), None, None).unwrap();
+    let accounts = [
+        fixture.wallet(sender, LAMPORTS_PER_SOL),
+        fixture.invoice(),
+        fixture.wallet(destination, 0),
+        keyed_account_for_system_program(),
+        fixture.mollusk.sysvars.keyed_account_for_clock_sysvar(),
+        fixture.config(),
+        (treasury, treasury_account),
+    ];
+
+    let result = fixture.mollusk.process_and_validate_instruction(
+        &ix,
+        &accounts,
+        &[
+            Check::success(),
+            Check::return_data(&0u64.to_le_bytes()),
+            Check::account(&sender).lamports(LAMPORTS_PER_SOL - AMOUNT).build(),
+            Check::account(&destination).lamports(AMOUNT - AMOUNT / 100).build(),
+            Check::account(&treasury).lamports(treasury_lamports + AMOUNT / 100).build(),
+        ],
+    );
+
+    assert!(
+        result.compute_units_consumed <= PAY_INVOICE_BUDGET,
+        "pay_invoice consumed {} CUs, over its budget of {PAY_INVOICE_BUDGET}",
+        result.compute_units_consumed,
+    );
+}
+
+#[test]
+fn pay_invoice_partial_compute_units() {
+    let fixture = Fixture::new();
+    let sender = Pubkey::new_unique();
+    let destination = Pubkey::new_from_array(fixture.args.destination);
+
+    let ix = pay_invoice_ix(&fixture.program_id, &sender, &fixture.invoice_state(), None, Some(AMOUNT / 2)).unwrap();
+    let accounts = [
+        fixture.wallet(sender, LAMPORTS_PER_SOL),
+        fixture.invoice(),
+        fixture.wallet(destination, LAMPORTS_PER_SOL),
+        keyed_account_for_system_program(),
+        fixture.mollusk.sysvars.keyed_account_for_clock_sysvar(),
+        fixture.config(),
+        fixture.treasury(),
+    ];
+
+    let result = fixture.mollusk.process_and_validate_instruction(
+        &ix,
+        &accounts,
+        &[Check::success(), Check::return_data(&(AMOUNT / 2).to_le_bytes())],
+    );
+
+    assert!(
+        result.compute_units_consumed <= PAY_INVOICE_BUDGET,
+        "pay_invoice consumed {} CUs, over its budget of {PAY_INVOICE_BUDGET}",
+        result.compute_units_consumed,
+    );
+}
+
+#[test]
+fn pay_split_invoice_compute_units() {
+    let mut fixture = Fixture::new();
+    let sender = Pubkey::new_unique();
+    let destination = Pubkey::new_from_array(fixture.args.destination);
+    let platform = Pubkey::new_unique();
+    let affiliate = Pubkey::new_unique();
+    fixture.args.splits = vec![
+        Split { recipient: platform, share: Share::Bps(1_000) },
+        Split { recipient: affiliate, share: Share::Fixed(AMOUNT / 10) },
+    ];
+
+    let ix = pay_invoice_ix(&fixture.program_id, &sender, &fixture.invoice_state(), None, None).unwrap();
+    let accounts = [
+        fixture.wallet(sender, LAMPORTS_PER_SOL),
+        fixture.invoice(),
+        fixture.wallet(destination, 0),
+        keyed_account_for_system_program(),
+        fixture.mollusk.sysvars.keyed_account_for_clock_sysvar(),
+        fixture.config(),
+        fixture.treasury(),
+        fixture.wallet(platform, 0),
+        fixture.wallet(affiliate, 0),
+    ];
+
+    // 10% of the amount net of the 1% fee goes to the platform
+    let net_amount = AMOUNT - AMOUNT / 100;
+    let result = fixture.mollusk.process_and_validate_instruction(
+        &ix,
+        &accounts,
+        &[
+            Check::success(),
+            Check::account(&platform).lamports(net_amount / 10).build(),
+            Check::account(&affiliate).lamports(AMOUNT / 10).build(),
+            Check::account(&destination).lamports(net_amount - net_amount / 10 - AMOUNT / 10).build(),
+        ],
+    );
+
+    assert!(
+        result.compute_units_consumed <= PAY_SPLIT_INVOICE_BUDGET,
+        "pay_invoice with splits consumed {} CUs, over its budget of {PAY_SPLIT_INVOICE_BUDGET}",
+        result.compute_units_consumed,
+    );
+}
+
+#[test]
+fn pay_token_invoice_compute_units() {
+    let mut fixture = Fixture::new();
+    let token_program = fixture.add_token_program();
+    let sender = Pubkey::new_unique();
+    let destination = Pubkey::new_from_array(fixture.args.destination);
+    let (treasury, _) = find_treasury_address(&fixture.program_id);
+    let mint = Pubkey::new_unique();
+    fixture.args.mint = Some(mint);
+
+    let ix = pay_invoice_ix(&fixture.program_id, &sender, &fixture.invoice_state(), Some(&SPL_TOKEN_ID), None).unwrap();
+    let accounts = [
+        fixture.wallet(sender, LAMPORTS_PER_SOL),
+        fixture.invoice(),
+        fixture.wallet(destination, 0),
+        keyed_account_for_system_program(),
+        fixture.mollusk.sysvars.keyed_account_for_clock_sysvar(),
+        fixture.config(),
+        fixture.treasury(),
+        fixture.mint(mint),
+        token_program,
+        fixture.token_account(&sender, &mint, AMOUNT),
+        fixture.token_account(&destination, &mint, 0),
+        fixture.token_account(&treasury, &mint, 0),
+    ];
+
+    let result = fixture.mollusk.process_and_validate_instruction(&ix, &accounts, &[Check::success(), Check::return_data(&0u64.to_le_bytes())]);
+
+    assert!(
+        result.compute_units_consumed <= PAY_TOKEN_INVOICE_BUDGET,
+        "pay_invoice in tokens consumed {} CUs, over its budget of {PAY_TOKEN_INVOICE_BUDGET}",
+        result.compute_units_consumed,
+    );
+}
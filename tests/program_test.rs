@@ -0,0 +1,1124 @@
+//! End-to-end tests running the program in `solana-program-test`.
+
+use otus_program::{
+    client::{
+        create_invoice_ix, find_config_address, find_invoice_address, find_legacy_invoice_address, find_merchant_address, find_treasury_address,
+        find_vault_address, pay_invoice_ix,
+    },
+    error::InvoiceError,
+    instruction::{CreateInvoiceArgs, CreateSubscriptionArgs, EscrowArgs, InstructionData},
+    process_instruction,
+    state::{
+        decode_account, AccountState, Escrow, Invoice, InvoiceMetadata, InvoiceStatus, ProgramAccount, Share, Split, Subscription, LEGACY_ADMIN,
+        SUBSCRIPTION_SEED,
+    },
+};
+use solana_program_test::{processor, BanksClientError, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::{Account, AccountSharedData},
+    clock::Clock,
+    instruction::{AccountMeta, Instruction, InstructionError},
+    native_token::LAMPORTS_PER_SOL,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+    sysvar,
+    transaction::{Transaction, TransactionError},
+};
+use solana_sdk_ids::{bpf_loader_upgradeable, system_program};
+use solana_system_interface::instruction as system_instruction;
+use spl_associated_token_account_client::{address::get_associated_token_address_with_program_id, instruction::create_associated_token_account};
+use spl_token_2022::{error::TokenError, extension::StateWithExtensions, state::Mint};
+
+const SPL_TOKEN_ID: Pubkey = Pubkey::from_str_const("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA");
+
+struct TestContext {
+    context: ProgramTestContext,
+    program_id: Pubkey,
+    admin: Keypair,
+}
+
+/// Starts the program with `admin` as upgrade authority, initializes its config and registers
+/// the admin as a merchant.
+async fn setup() -> TestContext {
+    let mut test = start().await;
+    test.initialize().await.unwrap();
+    test
+}
+
+/// Starts the program with `admin` as upgrade authority, before its config is initialized.
+async fn start() -> TestContext {
+    let program_id = Pubkey::new_unique();
+    let admin = Keypair::new();
+
+    let mut program_test = ProgramTest::new("otus_program", program_id, processor!(process_instruction));
+    program_test.add_account(admin.pubkey(), Account { lamports: 10 * LAMPORTS_PER_SOL, ..Account::default() });
+
+    // Bincode layout of `UpgradeableLoaderState::ProgramData`: u32 variant, u64 slot, Option<Pubkey>
+    let program_data = [&3u32.to_le_bytes()[..], &0u64.to_le_bytes(), &[1], admin.pubkey().as_ref()].concat();
+    program_test.add_account(
+        program_data_address(&program_id),
+        Account { lamports: LAMPORTS_PER_SOL, data: program_data, owner: bpf_loader_upgradeable::id(), ..Account::default() },
+    );
+
+    TestContext { context: program_test.start_with_context().await, program_id, admin }
+}
+
+fn program_data_address(program_id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id()).0
+}
+
+fn invoice_args(id: u128, amount: u64, destination: &Pubkey) -> CreateInvoiceArgs {
+    CreateInvoiceArgs {
+        id,
+        amount,
+        destination: destination.to_bytes(),
+        rent_receiver: None,
+        mint: None,
+        due_at: None,
+        expires_at: None,
+        escrow: None,
+        splits: vec![],
+        metadata: InvoiceMetadata::default(),
+    }
+}
+
+fn token_error(error: TokenError) -> TransactionError {
+    TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+}
+
+fn custom_error(error: InvoiceError) -> TransactionError {
+    TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+}
+
+impl TestContext {
+    /// Initializes the config and registers the admin as a merchant.
+    async fn initialize(&mut self) -> Result<(), BanksClientError> {
+        let program_id = self.program_id;
+        let admin = self.admin.insecure_clone();
+
+        let initialize_config = Instruction::new_with_borsh(
+            program_id,
+            &InstructionData::InitializeConfig { admin: admin.pubkey() },
+            vec![
+                AccountMeta::new(admin.pubkey(), true),
+                AccountMeta::new(find_config_address(&program_id).0, false),
+                AccountMeta::new_readonly(program_data_address(&program_id), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new(find_treasury_address(&program_id).0, false),
+            ],
+        );
+        let register_merchant = Instruction::new_with_borsh(
+            program_id,
+            &InstructionData::RegisterMerchant { merchant: admin.pubkey() },
+            vec![
+                AccountMeta::new(admin.pubkey(), true),
+                AccountMeta::new_readonly(find_config_address(&program_id).0, false),
+                AccountMeta::new(find_merchant_address(&program_id, &admin.pubkey()).0, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+            ],
+        );
+        self.process(&[initialize_config, register_merchant], &[&admin]).await
+    }
+
+    /// Sends `instructions` in a transaction paid by the context payer and signed by `signers`.
+    async fn process(&mut self, instructions: &[Instruction], signers: &[&Keypair]) -> Result<(), BanksClientError> {
+        let blockhash = self.context.get_new_latest_blockhash().await.unwrap();
+        let signers = [&[&self.context.payer], signers].concat();
+        let transaction = Transaction::new_signed_with_payer(instructions, Some(&self.context.payer.pubkey()), &signers, blockhash);
+        self.context.banks_client.process_transaction(transaction).await
+    }
+
+    /// Fresh wallet funded with `lamports` by the context payer.
+    async fn wallet(&mut self, lamports: u64) -> Keypair {
+        let wallet = Keypair::new();
+        let transfer = system_instruction::transfer(&self.context.payer.pubkey(), &wallet.pubkey(), lamports);
+        self.process(&[transfer], &[]).await.unwrap();
+        wallet
+    }
+
+    async fn create_invoice(&mut self, args: CreateInvoiceArgs) -> Result<(), BanksClientError> {
+        let admin = self.admin.insecure_clone();
+        let ix = create_invoice_ix(&self.program_id, &admin.pubkey(), None, args).unwrap();
+        self.process(&[ix], &[&admin]).await
+    }
+
+    /// Invoice as stored by the admin creating it with `args`, before any payment.
+    fn open_invoice(&self, args: &CreateInvoiceArgs) -> Invoice {
+        let (address, bump) = find_invoice_address(&self.program_id, &self.admin.pubkey(), args.id);
+        Invoice {
+            id: args.id,
+            issuer: self.admin.pubkey(),
+            amount: args.amount,
+            fee: 0,
+            amount_paid: 0,
+            amount_received: 0,
+            amount_refunded: 0,
+            status: InvoiceStatus::Open,
+            destination: args.destination,
+            rent_receiver: args.rent_receiver.unwrap_or(self.admin.pubkey()),
+            mint: args.mint,
+            due_at: args.due_at.unwrap_or(0),
+            expires_at: args.expires_at.unwrap_or(0),
+            late: false,
+            payer: Pubkey::default(),
+            paid_at: 0,
+            paid_slot: 0,
+            escrow: args.escrow.as_ref().map(|escrow| Escrow {
+                arbiter: escrow.arbiter,
+                timeout: escrow.timeout,
+                settled: false,
+                vault_bump: find_vault_address(&self.program_id, &address).1,
+            }),
+            splits: args.splits.clone(),
+            legacy_address: false,
+            bump,
+        }
+    }
+
+    fn pay_invoice_ix(&self, sender: &Pubkey, args: &CreateInvoiceArgs, amount: Option<u64>) -> Instruction {
+        pay_invoice_ix(&self.program_id, sender, &self.open_invoice(args), None, amount).unwrap()
+    }
+
+    async fn set_fee(&mut self, fee_bps: u16) {
+        let admin = self.admin.insecure_clone();
+        let set_fee = Instruction::new_with_borsh(
+            self.program_id,
+            &InstructionData::SetFee { fee_bps, fee_min: 0 },
+            vec![AccountMeta::new_readonly(admin.pubkey(), true), AccountMeta::new(find_config_address(&self.program_id).0, false)],
+        );
+        self.process(&[set_fee], &[&admin]).await.unwrap();
+    }
+
+    async fn balance(&mut self, address: &Pubkey) -> u64 {
+        self.context.banks_client.get_balance(*address).await.unwrap()
+    }
+
+    /// Creates a mint owned by `token_program`, the context payer being its mint authority.
+    async fn create_mint(&mut self, token_program: &Pubkey, decimals: u8) -> Pubkey {
+        let mint = Keypair::new();
+        let payer = self.context.payer.pubkey();
+        let rent = self.context.banks_client.get_rent().await.unwrap();
+        let instructions = [
+            system_instruction::create_account(&payer, &mint.pubkey(), rent.minimum_balance(Mint::LEN), Mint::LEN as u64, token_program),
+            spl_token_2022::instruction::initialize_mint2(token_program, &mint.pubkey(), &payer, None, decimals).unwrap(),
+        ];
+        self.process(&instructions, &[&mint]).await.unwrap();
+        mint.pubkey()
+    }
+
+    /// Creates the associated token account of `wallet` for `mint` and mints `amount` to it.
+    async fn token_account(&mut self, wallet: &Pubkey, mint: &Pubkey, token_program: &Pubkey, amount: u64) -> Pubkey {
+        let payer = self.context.payer.pubkey();
+        let address = get_associated_token_address_with_program_id(wallet, mint, token_program);
+        let mut instructions = vec![create_associated_token_account(&payer, wallet, mint, token_program)];
+        if amount > 0 {
+            instructions.push(spl_token_2022::instruction::mint_to(token_program, mint, &address, &payer, &[], amount).unwrap());
+        }
+        self.process(&instructions, &[]).await.unwrap();
+        address
+    }
+
+    async fn token_balance(&mut self, address: &Pubkey) -> u64 {
+        let account = self.account(address).await.unwrap();
+        StateWithExtensions::<spl_token_2022::state::Account>::unpack(&account.data).unwrap().base.amount
+    }
+
+    /// Refunds `amount` of the invoice `id` from its destination to `payer`, in tokens of `mint`
+    /// owned by `token_program` when given.
+    fn refund_ix(&self, destination: &Pubkey, payer: &Pubkey, id: u128, amount: u64, token: Option<(&Pubkey, &Pubkey)>) -> Instruction {
+        let (invoice, _) = find_invoice_address(&self.program_id, &self.admin.pubkey(), id);
+        let mut accounts = vec![
+            AccountMeta::new(*destination, true),
+            AccountMeta::new(invoice, false),
+            AccountMeta::new(*payer, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ];
+        if let Some((mint, token_program)) = token {
+            accounts.extend([
+                AccountMeta::new_readonly(*mint, false),
+                AccountMeta::new_readonly(*token_program, false),
+                AccountMeta::new(get_associated_token_address_with_program_id(destination, mint, token_program), false),
+                AccountMeta::new(get_associated_token_address_with_program_id(payer, mint, token_program), false),
+            ]);
+        }
+
+        Instruction::new_with_borsh(self.program_id, &InstructionData::RefundInvoice { amount }, accounts)
+    }
+
+    /// Releases the funds of the escrow invoice `id`, paid in tokens of `mint`, to its destination.
+    fn release_escrow_ix(&self, authority: &Pubkey, args: &CreateInvoiceArgs, token_program: &Pubkey) -> Instruction {
+        let (invoice, _) = find_invoice_address(&self.program_id, &self.admin.pubkey(), args.id);
+        let (treasury, _) = find_treasury_address(&self.program_id);
+        let destination = Pubkey::new_from_array(args.destination);
+        let mint = args.mint.unwrap();
+
+        Instruction::new_with_borsh(
+            self.program_id,
+            &InstructionData::ReleaseEscrow,
+            vec![
+                AccountMeta::new_readonly(*authority, true),
+                AccountMeta::new(invoice, false),
+                AccountMeta::new(find_vault_address(&self.program_id, &invoice).0, false),
+                AccountMeta::new(destination, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(sysvar::clock::id(), false),
+                AccountMeta::new_readonly(find_config_address(&self.program_id).0, false),
+                AccountMeta::new(treasury, false),
+                AccountMeta::new_readonly(mint, false),
+                AccountMeta::new_readonly(*token_program, false),
+                AccountMeta::new(get_associated_token_address_with_program_id(&destination, &mint, token_program), false),
+                AccountMeta::new(get_associated_token_address_with_program_id(&treasury, &mint, token_program), false),
+            ],
+        )
+    }
+
+    /// Returns the funds of the escrow invoice `id`, paid in tokens of `mint`, to `payer`.
+    fn dispute_refund_ix(&self, authority: &Pubkey, payer: &Pubkey, args: &CreateInvoiceArgs, token_program: &Pubkey) -> Instruction {
+        let (invoice, _) = find_invoice_address(&self.program_id, &self.admin.pubkey(), args.id);
+        let mint = args.mint.unwrap();
+
+        Instruction::new_with_borsh(
+            self.program_id,
+            &InstructionData::DisputeRefund,
+            vec![
+                AccountMeta::new_readonly(*authority, true),
+                AccountMeta::new(invoice, false),
+                AccountMeta::new(find_vault_address(&self.program_id, &invoice).0, false),
+                AccountMeta::new(*payer, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(mint, false),
+                AccountMeta::new_readonly(*token_program, false),
+                AccountMeta::new(get_associated_token_address_with_program_id(payer, &mint, token_program), false),
+            ],
+        )
+    }
+
+    /// Moves the clock `seconds` forward.
+    async fn advance_clock(&mut self, seconds: i64) {
+        let mut clock = self.context.banks_client.get_sysvar::<Clock>().await.unwrap();
+        clock.unix_timestamp += seconds;
+        self.context.set_sysvar(&clock);
+    }
+
+    async fn account(&mut self, address: &Pubkey) -> Option<Account> {
+        self.context.banks_client.get_account(*address).await.unwrap()
+    }
+
+    /// Stores an invoice as the first program version did: id, amount, paid flag and destination,
+    /// without header, at the address derived from its id. Returns that address.
+    async fn add_legacy_invoice(&mut self, id: u128, amount: u64, paid: bool, destination: &Pubkey) -> Pubkey {
+        let (address, _) = find_legacy_invoice_address(&self.program_id, id);
+        let data = [id.to_le_bytes().as_slice(), &amount.to_le_bytes(), &[u8::from(paid)], destination.as_ref()].concat();
+        let lamports = self.context.banks_client.get_rent().await.unwrap().minimum_balance(data.len());
+        self.context.set_account(&address, &Account { lamports, data, owner: self.program_id, ..Account::default() }.into());
+        address
+    }
+
+    async fn migrate_invoice(&mut self, address: &Pubkey, payer: &Keypair) -> Result<(), BanksClientError> {
+        let migrate = Instruction::new_with_borsh(
+            self.program_id,
+            &InstructionData::MigrateInvoice,
+            vec![
+                AccountMeta::new(payer.pubkey(), true),
+                AccountMeta::new(*address, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+            ],
+        );
+        self.process(&[migrate], &[payer]).await
+    }
+
+    async fn subscription(&mut self, address: &Pubkey) -> Subscription {
+        let account = self.account(address).await.unwrap();
+        let ProgramAccount::Subscription(state) = decode_account(&account.data).unwrap() else {
+            panic!("not a subscription account");
+        };
+        state
+    }
+
+    /// Charges the current cycle of the subscription at `address`, paid by `keeper`.
+    async fn charge_subscription(&mut self, address: &Pubkey, keeper: &Keypair, token_program: &Pubkey) -> Result<(), BanksClientError> {
+        let state = self.subscription(address).await;
+        let (invoice, _) = find_invoice_address(&self.program_id, &state.merchant, state.cycle_invoice_id(address));
+        let (treasury, _) = find_treasury_address(&self.program_id);
+        let token_account = |wallet: &Pubkey| get_associated_token_address_with_program_id(wallet, &state.mint, token_program);
+
+        let charge = Instruction::new_with_borsh(
+            self.program_id,
+            &InstructionData::ChargeSubscription,
+            vec![
+                AccountMeta::new(keeper.pubkey(), true),
+                AccountMeta::new(*address, false),
+                AccountMeta::new(invoice, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(sysvar::clock::id(), false),
+                AccountMeta::new_readonly(find_config_address(&self.program_id).0, false),
+                AccountMeta::new(treasury, false),
+                AccountMeta::new_readonly(state.mint, false),
+                AccountMeta::new_readonly(*token_program, false),
+                AccountMeta::new(token_account(&state.subscriber), false),
+                AccountMeta::new(token_account(&state.destination), false),
+                AccountMeta::new(token_account(&treasury), false),
+            ],
+        );
+        self.process(&[charge], &[keeper]).await
+    }
+
+    async fn invoice(&mut self, id: u128) -> ProgramAccount {
+        let (address, _) = find_invoice_address(&self.program_id, &self.admin.pubkey(), id);
+        let account = self.account(&address).await.unwrap();
+        assert_eq!(account.owner, self.program_id);
+        decode_account(&account.data).unwrap()
+    }
+}
+
+#[tokio::test]
+async fn initialize_config_takes_over_prefunded_treasury() {
+    let mut test = start().await;
+    let (treasury, _) = find_treasury_address(&test.program_id);
+    let minimum_balance = test.context.banks_client.get_rent().await.unwrap().minimum_balance(0);
+    let transfer = system_instruction::transfer(&test.context.payer.pubkey(), &treasury, minimum_balance);
+    test.process(&[transfer], &[]).await.unwrap();
+
+    test.initialize().await.unwrap();
+
+    let account = test.account(&treasury).await.unwrap();
+    assert_eq!(account.owner, test.program_id);
+    assert_eq!(account.lamports, minimum_balance);
+    assert!(matches!(decode_account(&test.account(&find_config_address(&test.program_id).0).await.unwrap().data), Ok(ProgramAccount::Config(_))));
+}
+
+#[tokio::test]
+async fn prefunded_program_addresses_dont_block_their_creation() {
+    let mut test = start().await;
+    let admin = test.admin.pubkey();
+    let (config, _) = find_config_address(&test.program_id);
+    let (merchant_registry, _) = find_merchant_address(&test.program_id, &admin);
+    let (invoice, _) = find_invoice_address(&test.program_id, &admin, 1);
+    let (vault, _) = find_vault_address(&test.program_id, &invoice);
+    let payer = test.context.payer.pubkey();
+    let rent = test.context.banks_client.get_rent().await.unwrap();
+    let transfers = [config, merchant_registry, vault].map(|address| system_instruction::transfer(&payer, &address, rent.minimum_balance(0)));
+    test.process(&transfers, &[]).await.unwrap();
+
+    test.initialize().await.unwrap();
+    let args = CreateInvoiceArgs { escrow: Some(EscrowArgs { arbiter: None, timeout: 60 }), ..invoice_args(1, 500_000_000, &Pubkey::new_unique()) };
+    test.create_invoice(args).await.unwrap();
+
+    for address in [config, merchant_registry, vault] {
+        let account = test.account(&address).await.unwrap();
+        assert_eq!(account.owner, test.program_id);
+        assert_eq!(account.lamports, rent.minimum_balance(account.data.len()));
+    }
+}
+
+#[tokio::test]
+async fn create_invoice_as_admin() {
+    let mut test = setup().await;
+    let destination = Pubkey::new_unique();
+
+    test.create_invoice(invoice_args(1, 500_000_000, &destination)).await.unwrap();
+
+    let ProgramAccount::Invoice(invoice, metadata) = test.invoice(1).await else {
+        panic!("not an invoice account");
+    };
+    assert_eq!(invoice.id, 1);
+    assert_eq!(invoice.issuer, test.admin.pubkey());
+    assert_eq!(invoice.amount, 500_000_000);
+    assert_eq!(invoice.amount_paid, 0);
+    assert_eq!(invoice.status, InvoiceStatus::Open);
+    assert_eq!(invoice.destination, destination.to_bytes());
+    assert_eq!(invoice.rent_receiver, test.admin.pubkey());
+    assert_eq!(metadata, InvoiceMetadata::default());
+}
+
+#[tokio::test]
+async fn pay_invoice_from_fresh_wallet() {
+    let mut test = setup().await;
+    let destination = Pubkey::new_unique();
+    let sender = test.wallet(LAMPORTS_PER_SOL).await;
+    let args = invoice_args(1, 500_000_000, &destination);
+    test.create_invoice(args.clone()).await.unwrap();
+
+    let ProgramAccount::Invoice(invoice, _) = test.invoice(1).await else {
+        panic!("not an invoice account");
+    };
+    assert_eq!(invoice, test.open_invoice(&args));
+    let pay = pay_invoice_ix(&test.program_id, &sender.pubkey(), &invoice, None, None).unwrap();
+    test.process(&[pay], &[&sender]).await.unwrap();
+
+    assert_eq!(test.balance(&sender.pubkey()).await, LAMPORTS_PER_SOL - 500_000_000);
+    assert_eq!(test.balance(&destination).await, 500_000_000);
+
+    let ProgramAccount::Invoice(invoice, _) = test.invoice(1).await else {
+        panic!("not an invoice account");
+    };
+    assert_eq!(invoice.amount_paid, 500_000_000);
+    assert_eq!(invoice.status, InvoiceStatus::Paid);
+    assert_eq!(invoice.payer, sender.pubkey());
+    assert!(invoice.paid_slot > 0);
+}
+
+#[tokio::test]
+async fn pay_invoice_in_parts_then_rejects_replay() {
+    let mut test = setup().await;
+    let destination = Pubkey::new_unique();
+    let sender = test.wallet(LAMPORTS_PER_SOL).await;
+    let args = invoice_args(1, 500_000_000, &destination);
+    test.create_invoice(args.clone()).await.unwrap();
+
+    let pay_part = test.pay_invoice_ix(&sender.pubkey(), &args, Some(200_000_000));
+    test.process(&[pay_part], &[&sender]).await.unwrap();
+
+    let ProgramAccount::Invoice(invoice, _) = test.invoice(1).await else {
+        panic!("not an invoice account");
+    };
+    assert_eq!(invoice.status, InvoiceStatus::PartiallyPaid);
+    assert_eq!(invoice.outstanding(), 300_000_000);
+
+    let pay_rest = test.pay_invoice_ix(&sender.pubkey(), &args, None);
+    test.process(std::slice::from_ref(&pay_rest), &[&sender]).await.unwrap();
+    assert_eq!(test.balance(&destination).await, 500_000_000);
+
+    let error = test.process(&[pay_rest], &[&sender]).await.unwrap_err().unwrap();
+    assert_eq!(error, custom_error(InvoiceError::AlreadyPaid));
+    assert_eq!(test.balance(&sender.pubkey()).await, LAMPORTS_PER_SOL - 500_000_000);
+}
+
+#[tokio::test]
+async fn pay_invoice_collects_protocol_fee() {
+    let mut test = setup().await;
+    let destination = Pubkey::new_unique();
+    let sender = test.wallet(LAMPORTS_PER_SOL).await;
+    let (treasury, _) = find_treasury_address(&test.program_id);
+    test.set_fee(100).await;
+
+    let args = invoice_args(1, 500_000_000, &destination);
+    test.create_invoice(args.clone()).await.unwrap();
+    let treasury_before = test.balance(&treasury).await;
+
+    let pay = test.pay_invoice_ix(&sender.pubkey(), &args, None);
+    test.process(&[pay], &[&sender]).await.unwrap();
+
+    assert_eq!(test.balance(&sender.pubkey()).await, LAMPORTS_PER_SOL - 500_000_000);
+    assert_eq!(test.balance(&destination).await, 495_000_000);
+    assert_eq!(test.balance(&treasury).await, treasury_before + 5_000_000);
+}
+
+#[tokio::test]
+async fn pay_invoice_splits_lamports_between_recipients() {
+    let mut test = setup().await;
+    let destination = Pubkey::new_unique();
+    let platform = Pubkey::new_unique();
+    let affiliate = Pubkey::new_unique();
+    let sender = test.wallet(LAMPORTS_PER_SOL).await;
+    let args = CreateInvoiceArgs {
+        splits: vec![
+            Split { recipient: platform, share: Share::Bps(1_000) },
+            Split { recipient: affiliate, share: Share::Fixed(20_000_000) },
+        ],
+        ..invoice_args(1, 500_000_000, &destination)
+    };
+    test.create_invoice(args.clone()).await.unwrap();
+
+    let pay = test.pay_invoice_ix(&sender.pubkey(), &args, None);
+    test.process(&[pay], &[&sender]).await.unwrap();
+
+    assert_eq!(test.balance(&sender.pubkey()).await, LAMPORTS_PER_SOL - 500_000_000);
+    assert_eq!(test.balance(&platform).await, 50_000_000);
+    assert_eq!(test.balance(&affiliate).await, 20_000_000);
+    assert_eq!(test.balance(&destination).await, 430_000_000);
+}
+
+#[tokio::test]
+async fn pay_invoice_charges_fee_on_fully_split_invoice() {
+    let mut test = setup().await;
+    let destination = Pubkey::new_unique();
+    let sender = test.wallet(LAMPORTS_PER_SOL).await;
+    let (treasury, _) = find_treasury_address(&test.program_id);
+    test.set_fee(100).await;
+
+    let args = CreateInvoiceArgs {
+        splits: vec![Split { recipient: destination, share: Share::Bps(10_000) }],
+        ..invoice_args(1, 500_000_000, &destination)
+    };
+    test.create_invoice(args.clone()).await.unwrap();
+    let treasury_before = test.balance(&treasury).await;
+
+    let pay = test.pay_invoice_ix(&sender.pubkey(), &args, None);
+    test.process(&[pay], &[&sender]).await.unwrap();
+
+    assert_eq!(test.balance(&sender.pubkey()).await, LAMPORTS_PER_SOL - 500_000_000);
+    assert_eq!(test.balance(&treasury).await, treasury_before + 5_000_000);
+    assert_eq!(test.balance(&destination).await, 495_000_000);
+}
+
+#[tokio::test]
+async fn pay_invoice_takes_fee_before_shares_of_fully_split_invoice() {
+    let mut test = setup().await;
+    let destination = Pubkey::new_unique();
+    let platform = Pubkey::new_unique();
+    let affiliate = Pubkey::new_unique();
+    let sender = test.wallet(LAMPORTS_PER_SOL).await;
+    let (treasury, _) = find_treasury_address(&test.program_id);
+    test.set_fee(100).await;
+
+    let args = CreateInvoiceArgs {
+        splits: vec![
+            Split { recipient: platform, share: Share::Bps(5_000) },
+            Split { recipient: affiliate, share: Share::Bps(5_000) },
+        ],
+        ..invoice_args(1, 500_000_000, &destination)
+    };
+    test.create_invoice(args.clone()).await.unwrap();
+    let treasury_before = test.balance(&treasury).await;
+
+    let pay_part = test.pay_invoice_ix(&sender.pubkey(), &args, Some(200_000_000));
+    test.process(&[pay_part], &[&sender]).await.unwrap();
+    // The invoice keeps the fee it was created with
+    test.set_fee(500).await;
+    let pay_rest = test.pay_invoice_ix(&sender.pubkey(), &args, None);
+    test.process(&[pay_rest], &[&sender]).await.unwrap();
+
+    // Both recipients get half of what's left after the 5_000_000 fee, whatever their order
+    assert_eq!(test.balance(&sender.pubkey()).await, LAMPORTS_PER_SOL - 500_000_000);
+    assert_eq!(test.balance(&treasury).await, treasury_before + 5_000_000);
+    assert_eq!(test.balance(&platform).await, 247_500_000);
+    assert_eq!(test.balance(&affiliate).await, 247_500_000);
+    assert_eq!(test.balance(&destination).await, 0);
+}
+
+#[tokio::test]
+async fn refund_invoice_returns_lamports_to_payer() {
+    let mut test = setup().await;
+    let destination = test.wallet(LAMPORTS_PER_SOL).await;
+    let sender = test.wallet(LAMPORTS_PER_SOL).await;
+    let args = invoice_args(1, 500_000_000, &destination.pubkey());
+    test.create_invoice(args.clone()).await.unwrap();
+
+    let pay = test.pay_invoice_ix(&sender.pubkey(), &args, None);
+    test.process(&[pay], &[&sender]).await.unwrap();
+
+    let (invoice, _) = find_invoice_address(&test.program_id, &test.admin.pubkey(), 1);
+    let refund = Instruction::new_with_borsh(
+        test.program_id,
+        &InstructionData::RefundInvoice { amount: 200_000_000 },
+        vec![
+            AccountMeta::new(destination.pubkey(), true),
+            AccountMeta::new(invoice, false),
+            AccountMeta::new(sender.pubkey(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    );
+    test.process(&[refund], &[&destination]).await.unwrap();
+
+    assert_eq!(test.balance(&sender.pubkey()).await, LAMPORTS_PER_SOL - 300_000_000);
+    assert_eq!(test.balance(&destination.pubkey()).await, LAMPORTS_PER_SOL + 300_000_000);
+
+    let ProgramAccount::Invoice(invoice, _) = test.invoice(1).await else {
+        panic!("not an invoice account");
+    };
+    assert_eq!(invoice.amount_refunded, 200_000_000);
+    assert_eq!(invoice.status, InvoiceStatus::PartiallyRefunded);
+}
+
+#[tokio::test]
+async fn pay_and_refund_token_invoice() {
+    for token_program in [SPL_TOKEN_ID, spl_token_2022::id()] {
+        let mut test = setup().await;
+        let destination = test.wallet(LAMPORTS_PER_SOL).await;
+        let sender = test.wallet(LAMPORTS_PER_SOL).await;
+        let (treasury, _) = find_treasury_address(&test.program_id);
+        test.set_fee(100).await;
+
+        let mint = test.create_mint(&token_program, 6).await;
+        let sender_tokens = test.token_account(&sender.pubkey(), &mint, &token_program, 1_000_000).await;
+        let destination_tokens = test.token_account(&destination.pubkey(), &mint, &token_program, 0).await;
+        let treasury_tokens = test.token_account(&treasury, &mint, &token_program, 0).await;
+
+        let args = CreateInvoiceArgs { mint: Some(mint), ..invoice_args(1, 500_000, &destination.pubkey()) };
+        test.create_invoice(args.clone()).await.unwrap();
+
+        let pay = pay_invoice_ix(&test.program_id, &sender.pubkey(), &test.open_invoice(&args), Some(&token_program), None).unwrap();
+        test.process(&[pay], &[&sender]).await.unwrap();
+
+        assert_eq!(test.token_balance(&sender_tokens).await, 500_000);
+        assert_eq!(test.token_balance(&destination_tokens).await, 495_000);
+        assert_eq!(test.token_balance(&treasury_tokens).await, 5_000);
+
+        let refund = test.refund_ix(&destination.pubkey(), &sender.pubkey(), 1, 200_000, Some((&mint, &token_program)));
+        test.process(&[refund], &[&destination]).await.unwrap();
+
+        assert_eq!(test.token_balance(&sender_tokens).await, 700_000);
+        assert_eq!(test.token_balance(&destination_tokens).await, 295_000);
+
+        let ProgramAccount::Invoice(invoice, _) = test.invoice(1).await else {
+            panic!("not an invoice account");
+        };
+        assert_eq!(invoice.amount_refunded, 200_000);
+        assert_eq!(invoice.status, InvoiceStatus::PartiallyRefunded);
+    }
+}
+
+#[tokio::test]
+async fn pay_token_invoice_rejects_mismatched_mint() {
+    for token_program in [SPL_TOKEN_ID, spl_token_2022::id()] {
+        let mut test = setup().await;
+        let destination = Pubkey::new_unique();
+        let sender = test.wallet(LAMPORTS_PER_SOL).await;
+        let (treasury, _) = find_treasury_address(&test.program_id);
+
+        let mint = test.create_mint(&token_program, 6).await;
+        let other_mint = test.create_mint(&token_program, 9).await;
+        let sender_tokens = test.token_account(&sender.pubkey(), &mint, &token_program, 1_000_000).await;
+        let sender_other_tokens = test.token_account(&sender.pubkey(), &other_mint, &token_program, 1_000_000_000).await;
+        test.token_account(&destination, &mint, &token_program, 0).await;
+        test.token_account(&treasury, &mint, &token_program, 0).await;
+
+        let args = CreateInvoiceArgs { mint: Some(mint), ..invoice_args(1, 500_000, &destination) };
+        test.create_invoice(args.clone()).await.unwrap();
+        let pay = pay_invoice_ix(&test.program_id, &sender.pubkey(), &test.open_invoice(&args), Some(&token_program), None).unwrap();
+
+        // A mint other than the invoice one, with other decimals, is rejected before any transfer
+        let mut pay_other_mint = pay.clone();
+        pay_other_mint.accounts[7].pubkey = other_mint;
+        let error = test.process(&[pay_other_mint], &[&sender]).await.unwrap_err().unwrap();
+        assert_eq!(error, custom_error(InvoiceError::InvalidMint));
+
+        // Tokens of another mint can't pay the invoice either
+        let mut pay_other_tokens = pay;
+        pay_other_tokens.accounts[9].pubkey = sender_other_tokens;
+        let error = test.process(&[pay_other_tokens], &[&sender]).await.unwrap_err().unwrap();
+        assert_eq!(error, token_error(TokenError::MintMismatch));
+
+        assert_eq!(test.token_balance(&sender_tokens).await, 1_000_000);
+        assert_eq!(test.token_balance(&sender_other_tokens).await, 1_000_000_000);
+    }
+}
+
+/// Escrow invoice of 500_000 tokens, timing out after an hour, paid by a fresh sender.
+struct TokenEscrow {
+    args: CreateInvoiceArgs,
+    token_program: Pubkey,
+    sender: Keypair,
+    arbiter: Keypair,
+    sender_tokens: Pubkey,
+    destination_tokens: Pubkey,
+    treasury_tokens: Pubkey,
+    vault: Pubkey,
+}
+
+async fn paid_token_escrow(test: &mut TestContext, token_program: Pubkey) -> TokenEscrow {
+    let destination = Pubkey::new_unique();
+    let sender = test.wallet(LAMPORTS_PER_SOL).await;
+    let arbiter = test.wallet(LAMPORTS_PER_SOL).await;
+    let (treasury, _) = find_treasury_address(&test.program_id);
+
+    let mint = test.create_mint(&token_program, 6).await;
+    let sender_tokens = test.token_account(&sender.pubkey(), &mint, &token_program, 1_000_000).await;
+    let destination_tokens = test.token_account(&destination, &mint, &token_program, 0).await;
+    let treasury_tokens = test.token_account(&treasury, &mint, &token_program, 0).await;
+
+    let args = CreateInvoiceArgs {
+        mint: Some(mint),
+        escrow: Some(EscrowArgs { arbiter: Some(arbiter.pubkey()), timeout: 3_600 }),
+        ..invoice_args(1, 500_000, &destination)
+    };
+    let admin = test.admin.insecure_clone();
+    let create = create_invoice_ix(&test.program_id, &admin.pubkey(), Some(&token_program), args.clone()).unwrap();
+    test.process(&[create], &[&admin]).await.unwrap();
+
+    let pay = pay_invoice_ix(&test.program_id, &sender.pubkey(), &test.open_invoice(&args), Some(&token_program), None).unwrap();
+    test.process(&[pay], &[&sender]).await.unwrap();
+
+    let (invoice, _) = find_invoice_address(&test.program_id, &admin.pubkey(), 1);
+    let (vault, _) = find_vault_address(&test.program_id, &invoice);
+    TokenEscrow { args, token_program, sender, arbiter, sender_tokens, destination_tokens, treasury_tokens, vault }
+}
+
+#[tokio::test]
+async fn release_token_escrow_pays_destination_and_fee() {
+    for token_program in [SPL_TOKEN_ID, spl_token_2022::id()] {
+        let mut test = setup().await;
+        test.set_fee(100).await;
+        let escrow = paid_token_escrow(&mut test, token_program).await;
+
+        assert_eq!(test.token_balance(&escrow.sender_tokens).await, 500_000);
+        assert_eq!(test.token_balance(&escrow.vault).await, 500_000);
+        assert_eq!(test.token_balance(&escrow.destination_tokens).await, 0);
+
+        let release = test.release_escrow_ix(&escrow.sender.pubkey(), &escrow.args, &escrow.token_program);
+        test.process(&[release], &[&escrow.sender]).await.unwrap();
+
+        assert_eq!(test.token_balance(&escrow.vault).await, 0);
+        assert_eq!(test.token_balance(&escrow.destination_tokens).await, 495_000);
+        assert_eq!(test.token_balance(&escrow.treasury_tokens).await, 5_000);
+
+        let ProgramAccount::Invoice(invoice, _) = test.invoice(1).await else {
+            panic!("not an invoice account");
+        };
+        assert!(invoice.escrow.unwrap().settled);
+        assert_eq!(invoice.amount_received, 495_000);
+    }
+}
+
+#[tokio::test]
+async fn dispute_refund_returns_escrowed_tokens() {
+    let mut test = setup().await;
+    test.set_fee(100).await;
+    let escrow = paid_token_escrow(&mut test, spl_token_2022::id()).await;
+
+    let dispute = test.dispute_refund_ix(&escrow.arbiter.pubkey(), &escrow.sender.pubkey(), &escrow.args, &escrow.token_program);
+    test.process(&[dispute], &[&escrow.arbiter]).await.unwrap();
+
+    assert_eq!(test.token_balance(&escrow.sender_tokens).await, 1_000_000);
+    assert_eq!(test.token_balance(&escrow.vault).await, 0);
+    assert_eq!(test.token_balance(&escrow.treasury_tokens).await, 0);
+
+    let ProgramAccount::Invoice(invoice, _) = test.invoice(1).await else {
+        panic!("not an invoice account");
+    };
+    assert_eq!(invoice.status, InvoiceStatus::Refunded);
+
+    let release = test.release_escrow_ix(&escrow.sender.pubkey(), &escrow.args, &escrow.token_program);
+    let error = test.process(&[release], &[&escrow.sender]).await.unwrap_err().unwrap();
+    assert_eq!(error, custom_error(InvoiceError::EscrowSettled));
+}
+
+#[tokio::test]
+async fn issuer_claims_token_escrow_after_timeout() {
+    let mut test = setup().await;
+    let escrow = paid_token_escrow(&mut test, spl_token_2022::id()).await;
+    let admin = test.admin.insecure_clone();
+
+    let release = test.release_escrow_ix(&admin.pubkey(), &escrow.args, &escrow.token_program);
+    let error = test.process(std::slice::from_ref(&release), &[&admin]).await.unwrap_err().unwrap();
+    assert_eq!(error, custom_error(InvoiceError::EscrowLocked));
+
+    test.advance_clock(3_600).await;
+    let error = test.process(std::slice::from_ref(&release), &[&admin]).await.unwrap_err().unwrap();
+    assert_eq!(error, custom_error(InvoiceError::EscrowLocked));
+
+    test.advance_clock(1).await;
+    test.process(&[release], &[&admin]).await.unwrap();
+
+    assert_eq!(test.token_balance(&escrow.vault).await, 0);
+    assert_eq!(test.token_balance(&escrow.destination_tokens).await, 500_000);
+}
+
+#[tokio::test]
+async fn charge_subscription_pulls_delegated_tokens_each_period() {
+    const PERIOD: i64 = 86_400;
+    let token_program = spl_token_2022::id();
+    let mut test = setup().await;
+    let admin = test.admin.insecure_clone();
+    let subscriber = test.wallet(LAMPORTS_PER_SOL).await;
+    let keeper = test.wallet(LAMPORTS_PER_SOL).await;
+    let destination = Pubkey::new_unique();
+    let (treasury, _) = find_treasury_address(&test.program_id);
+    test.set_fee(100).await;
+
+    let mint = test.create_mint(&token_program, 6).await;
+    let subscriber_tokens = test.token_account(&subscriber.pubkey(), &mint, &token_program, 10_000).await;
+    let destination_tokens = test.token_account(&destination, &mint, &token_program, 0).await;
+    let treasury_tokens = test.token_account(&treasury, &mint, &token_program, 0).await;
+
+    let first_due_at = test.context.banks_client.get_sysvar::<Clock>().await.unwrap().unix_timestamp;
+    let (subscription, _) = Pubkey::find_program_address(&[SUBSCRIPTION_SEED, admin.pubkey().as_ref(), &1u128.to_be_bytes()], &test.program_id);
+    let create = Instruction::new_with_borsh(
+        test.program_id,
+        &InstructionData::CreateSubscription(CreateSubscriptionArgs { id: 1, destination, mint, amount: 1_000, period: PERIOD, first_due_at }),
+        vec![
+            AccountMeta::new(subscriber.pubkey(), true),
+            AccountMeta::new_readonly(admin.pubkey(), true),
+            AccountMeta::new(subscription, false),
+            AccountMeta::new_readonly(find_merchant_address(&test.program_id, &admin.pubkey()).0, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+    );
+    let approve = spl_token_2022::instruction::approve(&token_program, &subscriber_tokens, &subscription, &subscriber.pubkey(), &[], 3_000).unwrap();
+    test.process(&[create, approve], &[&subscriber, &admin]).await.unwrap();
+
+    let first_invoice = test.subscription(&subscription).await.cycle_invoice_id(&subscription);
+    test.charge_subscription(&subscription, &keeper, &token_program).await.unwrap();
+
+    assert_eq!(test.token_balance(&subscriber_tokens).await, 9_000);
+    assert_eq!(test.token_balance(&destination_tokens).await, 990);
+    assert_eq!(test.token_balance(&treasury_tokens).await, 10);
+
+    let state = test.subscription(&subscription).await;
+    assert_eq!(state.cycle, 1);
+    assert_eq!(state.next_due_at, first_due_at + PERIOD);
+
+    let ProgramAccount::Invoice(invoice, _) = test.invoice(first_invoice).await else {
+        panic!("not an invoice account");
+    };
+    assert_eq!(invoice.status, InvoiceStatus::Paid);
+    assert_eq!((invoice.amount, invoice.amount_paid, invoice.amount_received), (1_000, 1_000, 990));
+    assert_eq!(invoice.payer, subscriber.pubkey());
+    assert_eq!(invoice.rent_receiver, keeper.pubkey());
+    assert_eq!(invoice.due_at, first_due_at);
+
+    let error = test.charge_subscription(&subscription, &keeper, &token_program).await.unwrap_err().unwrap();
+    assert_eq!(error, custom_error(InvoiceError::SubscriptionNotDue));
+
+    // Two and a half periods later the missed second period is skipped, not billed
+    test.advance_clock(PERIOD * 5 / 2).await;
+    let second_invoice = test.subscription(&subscription).await.cycle_invoice_id(&subscription);
+    test.charge_subscription(&subscription, &keeper, &token_program).await.unwrap();
+
+    assert_eq!(test.token_balance(&subscriber_tokens).await, 8_000);
+    assert_eq!(test.token_balance(&destination_tokens).await, 1_980);
+
+    let state = test.subscription(&subscription).await;
+    assert_eq!(state.cycle, 2);
+    assert_eq!(state.next_due_at, first_due_at + 3 * PERIOD);
+
+    let ProgramAccount::Invoice(invoice, _) = test.invoice(second_invoice).await else {
+        panic!("not an invoice account");
+    };
+    assert_ne!(second_invoice, first_invoice);
+    assert_eq!(invoice.due_at, first_due_at + PERIOD);
+}
+
+#[tokio::test]
+async fn create_invoice_rejects_non_signer_merchant() {
+    let mut test = setup().await;
+    let args = invoice_args(1, 500_000_000, &Pubkey::new_unique());
+
+    let mut ix = create_invoice_ix(&test.program_id, &test.admin.pubkey(), None, args).unwrap();
+    ix.accounts[0].is_signer = false;
+    let error = test.process(&[ix], &[]).await.unwrap_err().unwrap();
+
+    assert_eq!(error, TransactionError::InstructionError(0, InstructionError::MissingRequiredSignature));
+    let (address, _) = find_invoice_address(&test.program_id, &test.admin.pubkey(), 1);
+    assert_eq!(test.account(&address).await, None);
+}
+
+#[tokio::test]
+async fn create_invoice_rejects_wrong_system_program() {
+    let mut test = setup().await;
+    let admin = test.admin.insecure_clone();
+    let args = invoice_args(1, 500_000_000, &Pubkey::new_unique());
+
+    let mut ix = create_invoice_ix(&test.program_id, &admin.pubkey(), None, args).unwrap();
+    ix.accounts[2].pubkey = Pubkey::new_unique();
+    let error = test.process(&[ix], &[&admin]).await.unwrap_err().unwrap();
+
+    assert_eq!(error, custom_error(InvoiceError::InvalidSystemProgram));
+
+    let (address, _) = find_invoice_address(&test.program_id, &admin.pubkey(), 1);
+    assert_eq!(test.account(&address).await, None);
+}
+
+#[tokio::test]
+async fn create_invoice_rejects_wrong_address() {
+    let mut test = setup().await;
+    let admin = test.admin.insecure_clone();
+    let args = invoice_args(1, 500_000_000, &Pubkey::new_unique());
+
+    let mut ix = create_invoice_ix(&test.program_id, &admin.pubkey(), None, args).unwrap();
+    ix.accounts[1].pubkey = find_invoice_address(&test.program_id, &admin.pubkey(), 2).0;
+    let error = test.process(&[ix], &[&admin]).await.unwrap_err().unwrap();
+
+    assert_eq!(error, custom_error(InvoiceError::InvalidInvoiceAddress));
+}
+
+#[tokio::test]
+async fn create_invoice_rejects_existing_invoice() {
+    let mut test = setup().await;
+    let args = invoice_args(1, 500_000_000, &Pubkey::new_unique());
+    test.create_invoice(args.clone()).await.unwrap();
+
+    let error = test.create_invoice(args).await.unwrap_err().unwrap();
+
+    assert_eq!(error, custom_error(InvoiceError::AlreadyExists));
+}
+
+#[tokio::test]
+async fn create_invoice_rejects_unregistered_merchant() {
+    let mut test = setup().await;
+    let merchant = test.wallet(LAMPORTS_PER_SOL).await;
+    let args = invoice_args(1, 500_000_000, &Pubkey::new_unique());
+
+    let ix = create_invoice_ix(&test.program_id, &merchant.pubkey(), None, args).unwrap();
+    let error = test.process(&[ix], &[&merchant]).await.unwrap_err().unwrap();
+
+    assert_eq!(error, custom_error(InvoiceError::MerchantNotRegistered));
+}
+
+#[tokio::test]
+async fn pay_invoice_rejects_non_signer_sender() {
+    let mut test = setup().await;
+    let sender = test.wallet(LAMPORTS_PER_SOL).await;
+    let args = invoice_args(1, 500_000_000, &Pubkey::new_unique());
+    test.create_invoice(args.clone()).await.unwrap();
+
+    let mut pay = test.pay_invoice_ix(&sender.pubkey(), &args, None);
+    pay.accounts[0].is_signer = false;
+    let error = test.process(&[pay], &[]).await.unwrap_err().unwrap();
+
+    assert_eq!(error, TransactionError::InstructionError(0, InstructionError::MissingRequiredSignature));
+    assert_eq!(test.balance(&sender.pubkey()).await, LAMPORTS_PER_SOL);
+}
+
+#[tokio::test]
+async fn pay_invoice_rejects_wrong_system_program() {
+    let mut test = setup().await;
+    let sender = test.wallet(LAMPORTS_PER_SOL).await;
+    let args = invoice_args(1, 500_000_000, &Pubkey::new_unique());
+    test.create_invoice(args.clone()).await.unwrap();
+
+    let mut pay = test.pay_invoice_ix(&sender.pubkey(), &args, None);
+    pay.accounts[3].pubkey = Pubkey::new_unique();
+    let error = test.process(&[pay], &[&sender]).await.unwrap_err().unwrap();
+
+    assert_eq!(error, custom_error(InvoiceError::InvalidSystemProgram));
+}
+
+#[tokio::test]
+async fn pay_invoice_rejects_destination_mismatch() {
+    let mut test = setup().await;
+    let sender = test.wallet(LAMPORTS_PER_SOL).await;
+    let args = invoice_args(1, 500_000_000, &Pubkey::new_unique());
+    test.create_invoice(args.clone()).await.unwrap();
+
+    let mut pay = test.pay_invoice_ix(&sender.pubkey(), &args, None);
+    let other = Pubkey::new_unique();
+    pay.accounts[2].pubkey = other;
+    let error = test.process(&[pay], &[&sender]).await.unwrap_err().unwrap();
+
+    assert_eq!(error, custom_error(InvoiceError::DestinationMismatch));
+    assert_eq!(test.balance(&other).await, 0);
+}
+
+#[tokio::test]
+async fn pay_invoice_rejects_missing_invoice() {
+    let mut test = setup().await;
+    let sender = test.wallet(LAMPORTS_PER_SOL).await;
+    let args = invoice_args(1, 500_000_000, &Pubkey::new_unique());
+
+    let pay = test.pay_invoice_ix(&sender.pubkey(), &args, None);
+    let error = test.process(&[pay], &[&sender]).await.unwrap_err().unwrap();
+
+    assert_eq!(error, custom_error(InvoiceError::WrongOwner));
+}
+
+#[tokio::test]
+async fn pay_invoice_rejects_empty_pda() {
+    let mut test = setup().await;
+    let sender = test.wallet(LAMPORTS_PER_SOL).await;
+    let args = invoice_args(1, 500_000_000, &Pubkey::new_unique());
+    let (address, _) = find_invoice_address(&test.program_id, &test.admin.pubkey(), 1);
+    test.context.set_account(&address, &AccountSharedData::new(LAMPORTS_PER_SOL, 0, &test.program_id));
+
+    let pay = test.pay_invoice_ix(&sender.pubkey(), &args, None);
+    let error = test.process(&[pay], &[&sender]).await.unwrap_err().unwrap();
+
+    assert_eq!(error, custom_error(InvoiceError::InvoiceNotFound));
+}
+
+#[tokio::test]
+async fn update_invoice_metadata_refunds_shrunk_rent_to_rent_receiver() {
+    let mut test = setup().await;
+    let admin = test.admin.insecure_clone();
+    let keeper = test.wallet(LAMPORTS_PER_SOL).await;
+    let memo = |memo: &str| InvoiceMetadata { memo: Some(memo.to_string()), line_items: vec![] };
+    let mut args = invoice_args(1, 500_000_000, &Pubkey::new_unique());
+    args.rent_receiver = Some(keeper.pubkey());
+    args.metadata = memo(&"march ".repeat(20));
+    test.create_invoice(args).await.unwrap();
+
+    let (address, _) = find_invoice_address(&test.program_id, &admin.pubkey(), 1);
+    let update = |rent_receiver: Pubkey| {
+        Instruction::new_with_borsh(
+            test.program_id,
+            &InstructionData::UpdateInvoiceMetadata(memo("april")),
+            vec![
+                AccountMeta::new(admin.pubkey(), true),
+                AccountMeta::new(address, false),
+                AccountMeta::new(rent_receiver, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+            ],
+        )
+    };
+    let (to_admin, to_keeper) = (update(admin.pubkey()), update(keeper.pubkey()));
+
+    let error = test.process(&[to_admin], &[&admin]).await.unwrap_err().unwrap();
+    assert_eq!(error, custom_error(InvoiceError::InvalidRentReceiver));
+
+    let lamports_before = test.balance(&address).await;
+    let admin_before = test.balance(&admin.pubkey()).await;
+    test.process(&[to_keeper], &[&admin]).await.unwrap();
+
+    let account = test.account(&address).await.unwrap();
+    let ProgramAccount::Invoice(_, metadata) = decode_account(&account.data).unwrap() else {
+        panic!("not an invoice account");
+    };
+    assert_eq!(metadata, memo("april"));
+    let minimum_balance = test.context.banks_client.get_rent().await.unwrap().minimum_balance(account.data.len());
+    assert_eq!(account.lamports, minimum_balance);
+    assert_eq!(test.balance(&keeper.pubkey()).await, LAMPORTS_PER_SOL + lamports_before - minimum_balance);
+    assert_eq!(test.balance(&admin.pubkey()).await, admin_before);
+}
+
+#[tokio::test]
+async fn migrate_invoice_upgrades_baseline_invoice() {
+    let mut test = setup().await;
+    let sender = test.wallet(LAMPORTS_PER_SOL).await;
+    let payer = test.wallet(LAMPORTS_PER_SOL).await;
+    let destination = Pubkey::new_unique();
+    let address = test.add_legacy_invoice(1, 500_000_000, false, &destination).await;
+
+    test.migrate_invoice(&address, &payer).await.unwrap();
+
+    let account = test.account(&address).await.unwrap();
+    let ProgramAccount::Invoice(invoice, _) = decode_account(&account.data).unwrap() else {
+        panic!("not an invoice account");
+    };
+    assert_eq!((invoice.id, invoice.amount, invoice.destination), (1, 500_000_000, destination.to_bytes()));
+    assert_eq!(invoice.status, InvoiceStatus::Open);
+    assert_eq!((invoice.issuer, invoice.rent_receiver), (LEGACY_ADMIN, LEGACY_ADMIN));
+    assert!(invoice.legacy_address);
+
+    let rent = test.context.banks_client.get_rent().await.unwrap();
+    let top_up = rent.minimum_balance(invoice.space().unwrap()) - rent.minimum_balance(57);
+    assert_eq!(account.data.len(), invoice.space().unwrap());
+    assert_eq!(account.lamports, rent.minimum_balance(invoice.space().unwrap()));
+    assert_eq!(test.balance(&payer.pubkey()).await, LAMPORTS_PER_SOL - top_up);
+
+    let pay = pay_invoice_ix(&test.program_id, &sender.pubkey(), &invoice, None, None).unwrap();
+    assert_eq!(pay.accounts[1].pubkey, address);
+    test.process(&[pay], &[&sender]).await.unwrap();
+    assert_eq!(test.balance(&destination).await, 500_000_000);
+
+    let error = test.migrate_invoice(&address, &payer).await.unwrap_err().unwrap();
+    assert_eq!(error, custom_error(InvoiceError::AccountUpToDate));
+}
+
+#[tokio::test]
+async fn migrate_invoice_keeps_baseline_invoice_paid() {
+    let mut test = setup().await;
+    let sender = test.wallet(LAMPORTS_PER_SOL).await;
+    let payer = test.wallet(LAMPORTS_PER_SOL).await;
+    let address = test.add_legacy_invoice(1, 500_000_000, true, &Pubkey::new_unique()).await;
+
+    test.migrate_invoice(&address, &payer).await.unwrap();
+
+    let ProgramAccount::Invoice(invoice, _) = decode_account(&test.account(&address).await.unwrap().data).unwrap() else {
+        panic!("not an invoice account");
+    };
+    assert_eq!(invoice.status, InvoiceStatus::Paid);
+    assert_eq!(invoice.amount_paid, 500_000_000);
+
+    let pay = pay_invoice_ix(&test.program_id, &sender.pubkey(), &invoice, None, None).unwrap();
+    let error = test.process(&[pay], &[&sender]).await.unwrap_err().unwrap();
+    assert_eq!(error, custom_error(InvoiceError::AlreadyPaid));
+}
+